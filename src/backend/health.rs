@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::{io, net, time};
+
+use slab::Slab;
+
+use super::{BackendId, Pool};
+use crate::config;
+use crate::kio::completion::{self, CompletionType};
+use crate::kio::task::{self, TaskId};
+use crate::kio::{buffer, tcp, Kio};
+
+// An in-flight health check against a single backend.
+struct Probe {
+    backend: BackendId,
+    addr: net::SocketAddr, // detects if the backend was replaced mid-check
+    reader: Option<tcp::Reader>, // set while the HTTP request is being written
+    writer: Option<tcp::Writer>, // used to send the HTTP request
+    response: Vec<u8>,     // HTTP response received so far
+}
+
+// Periodically checks every backend using tasks on the runtime, so checks never block the proxy.
+pub struct Checker {
+    config: config::Health,
+
+    probes: Slab<Probe>,
+    tasks: HashMap<TaskId, usize>, // maps tasks to probes
+    next: Option<time::Instant>,   // when the next round of checks is due
+}
+
+impl Checker {
+    // Don't bother reading a longer HTTP status line.
+    const MAX_RESPONSE: usize = 1024;
+
+    pub fn new(config: &config::Health) -> Self {
+        Self {
+            config: config.clone(),
+            probes: Slab::new(),
+            tasks: HashMap::new(),
+            next: None,
+        }
+    }
+
+    // Check every backend immediately and then on each interval, as long as tick is called.
+    pub fn start(&mut self, kio: &mut Kio, pool: &mut Pool) {
+        let interval = match self.config.interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        self.check(kio, pool);
+        self.next = Some(time::Instant::now() + interval);
+    }
+
+    // When the next round of checks is due, so the caller can wake up for it.
+    pub fn deadline(&self) -> Option<time::Instant> {
+        self.next
+    }
+
+    // Check every backend again if the interval has elapsed.
+    pub fn tick(&mut self, kio: &mut Kio, pool: &mut Pool) {
+        if let Some(next) = self.next {
+            if next <= time::Instant::now() {
+                self.start(kio, pool);
+            }
+        }
+    }
+
+    // Handle the completion if it belongs to a health check, otherwise return it.
+    pub fn complete(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut Pool,
+        task_id: TaskId,
+        completion: CompletionType,
+    ) -> Option<CompletionType> {
+        let probe_id = match self.tasks.remove(&task_id) {
+            Some(probe_id) => probe_id,
+            None => return Some(completion),
+        };
+
+        let result = match completion {
+            CompletionType::Connect(connect) => self.connected(kio, probe_id, connect),
+            CompletionType::Write(write) => self.written(kio, probe_id, write),
+            CompletionType::Read(read) => self.received(kio, probe_id, read),
+            _ => panic!("unknown health check completion"),
+        };
+
+        // The probe is done once it has a result.
+        if let Some(healthy) = result {
+            let probe = self.probes.remove(probe_id);
+
+            // Make sure the backend wasn't removed and replaced while we were checking.
+            if let Some(backend) = pool.get(probe.backend) {
+                if backend.addr == probe.addr {
+                    pool.report(probe.backend, healthy);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn check(&mut self, kio: &mut Kio, pool: &mut Pool) {
+        let backends: Vec<_> = pool
+            .iter()
+            .map(|(id, backend)| (id, backend.addr))
+            .collect();
+
+        for (id, addr) in backends {
+            // Skip backends that are still being checked from the last interval.
+            if self.probes.iter().any(|(_, probe)| probe.backend == id) {
+                continue;
+            }
+
+            let socket = match tcp::socket(&addr) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    println!("failed to create health check socket: {}", err);
+                    continue;
+                }
+            };
+
+            let (reader, writer) = kio.register(socket);
+
+            let probe_id = self.probes.insert(Probe {
+                backend: id,
+                addr,
+                reader: None,
+                writer: Some(writer),
+                response: Vec::new(),
+            });
+
+            let connect = task::Connect::new(reader, addr);
+            self.run(kio, connect.into(), probe_id);
+        }
+    }
+
+    fn run(&mut self, kio: &mut Kio, task: task::TaskType, probe_id: usize) {
+        let task_id = kio.run_timeout(task, self.config.timeout()).detach();
+        self.tasks.insert(task_id, probe_id);
+    }
+
+    fn connected(
+        &mut self,
+        kio: &mut Kio,
+        probe_id: usize,
+        connect: completion::Connect,
+    ) -> Option<bool> {
+        if connect.result.is_err() {
+            return Some(false);
+        }
+
+        // A successful connection is enough unless we need to make an HTTP request.
+        let path = match self.config.path {
+            Some(ref path) => path,
+            None => return Some(true),
+        };
+
+        let probe = &mut self.probes[probe_id];
+
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: wisp\r\nConnection: close\r\n\r\n",
+            path, probe.addr
+        );
+
+        let mut buffer = buffer::Slice::new(request.len());
+        buffer.copy_from_slice(request.as_bytes());
+
+        let writer = probe.writer.take().unwrap();
+
+        // Hold on to the reader until the request has been written.
+        probe.reader = Some(connect.task.socket);
+
+        let write = task::Write::new(writer, buffer, ..);
+        self.run(kio, write.into(), probe_id);
+
+        None
+    }
+
+    fn written(
+        &mut self,
+        kio: &mut Kio,
+        probe_id: usize,
+        write: completion::Write,
+    ) -> Option<bool> {
+        let task = write.task;
+
+        let size = match write.size {
+            Ok(size) => size,
+            Err(_) => return Some(false),
+        };
+
+        if size < task.end - task.start {
+            // Continue writing the rest of the request.
+            let write = task::Write::new(task.socket, task.buffer, task.start + size..task.end);
+            self.run(kio, write.into(), probe_id);
+
+            return None;
+        }
+
+        // Keep the writer open until the probe finishes, since some servers treat a
+        // half-closed connection as aborted.
+        let probe = &mut self.probes[probe_id];
+        probe.writer = Some(task.socket);
+
+        let read = task::Read {
+            socket: probe.reader.take().unwrap(),
+            buffer: buffer::Slice::new(Self::MAX_RESPONSE),
+        };
+        self.run(kio, read.into(), probe_id);
+
+        None
+    }
+
+    fn received(&mut self, kio: &mut Kio, probe_id: usize, read: completion::Read) -> Option<bool> {
+        let task = read.task;
+
+        let size = match read.size {
+            Ok(size) => size,
+            Err(_) => return Some(false),
+        };
+
+        let probe = &mut self.probes[probe_id];
+        probe.response.extend_from_slice(&task.buffer[..size]);
+
+        match status(&probe.response) {
+            Ok(Some(status)) => return Some(status == self.config.status),
+            Ok(None) if size > 0 && probe.response.len() < Self::MAX_RESPONSE => {}
+            _ => return Some(false),
+        }
+
+        // Keep reading until we have the full status line.
+        let read = task::Read {
+            socket: task.socket,
+            buffer: task.buffer,
+        };
+        self.run(kio, read.into(), probe_id);
+
+        None
+    }
+}
+
+// Parse the status code out of an HTTP response, returning None if the status line is incomplete.
+fn status(response: &[u8]) -> io::Result<Option<u16>> {
+    let end = match response.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+
+    let line = std::str::from_utf8(&response[..end])
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+
+    let mut parts = line.split(' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+            .parse()
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid status code")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid status line",
+        )),
+    }
+}
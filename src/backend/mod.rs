@@ -0,0 +1,234 @@
+use std::net;
+
+use slab::Slab;
+
+use crate::config;
+
+mod health;
+mod strategy;
+
+pub use health::*;
+pub use strategy::*;
+
+pub type BackendId = usize;
+
+pub struct Backend {
+    pub addr: net::SocketAddr,
+    pub weight: u32,
+
+    active: usize, // number of connections currently using this backend
+    removed: bool, // waiting for connections to finish before being freed
+
+    up: bool,       // passing health checks
+    successes: u32, // consecutive successful checks
+    failures: u32,  // consecutive failed checks
+}
+
+impl Backend {
+    fn new(addr: net::SocketAddr, weight: u32) -> Self {
+        Self {
+            addr,
+            weight,
+            active: 0,
+            removed: false,
+            up: true,
+            successes: 0,
+            failures: 0,
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    pub fn up(&self) -> bool {
+        self.up
+    }
+
+    // Returns true if new connections can be routed to this backend.
+    pub fn available(&self) -> bool {
+        self.up && !self.removed
+    }
+
+    // Returns true if this backend has fewer connections relative to its weight.
+    pub fn less_loaded(&self, other: &Backend) -> bool {
+        let lhs = self.active as u64 * other.weight as u64;
+        let rhs = other.active as u64 * self.weight as u64;
+        lhs < rhs
+    }
+}
+
+// A set of backends and the strategy used to pick between them.
+pub struct Pool {
+    backends: Slab<Backend>,
+    strategy: StrategyType,
+
+    rise: u32, // consecutive successes before a backend is marked up
+    fall: u32, // consecutive failures before a backend is marked down
+}
+
+impl Pool {
+    pub fn new(balance: config::Balance) -> Self {
+        let strategy = match balance {
+            config::Balance::RoundRobin => RoundRobin::default().into(),
+            config::Balance::LeastConnections => LeastConnections::default().into(),
+            config::Balance::RandomTwo => RandomTwo::default().into(),
+            config::Balance::ConsistentHash => ConsistentHash::default().into(),
+        };
+
+        Self {
+            backends: Slab::new(),
+            strategy,
+            rise: 1,
+            fall: 1,
+        }
+    }
+
+    pub fn from_config(config: &config::Config) -> Self {
+        let mut pool = Self::new(config.balance);
+        pool.rise = config.health.rise;
+        pool.fall = config.health.fall;
+
+        for backend in &config.backend {
+            pool.add(backend.addr, backend.weight);
+        }
+
+        pool
+    }
+
+    // Add a backend that can immediately be used for new connections.
+    pub fn add(&mut self, addr: net::SocketAddr, weight: u32) -> BackendId {
+        let id = self.backends.insert(Backend::new(addr, weight));
+
+        self.strategy.update(&self.backends);
+
+        id
+    }
+
+    // Stop routing new connections to the backend.
+    // It's freed once any existing connections have been released.
+    pub fn remove(&mut self, id: BackendId) {
+        let backend = match self.backends.get_mut(id) {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        backend.removed = true;
+
+        if backend.active == 0 {
+            self.backends.remove(id);
+        }
+
+        self.strategy.update(&self.backends);
+    }
+
+    // Switch to a new set of backends, keeping the health and connections of those that stay.
+    // Backends that leave are removed, and those that join can immediately be used.
+    pub fn replace(&mut self, backends: &[config::Backend]) {
+        let gone: Vec<BackendId> = self
+            .iter()
+            .filter(|(_, backend)| !backends.iter().any(|b| b.addr == backend.addr))
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in gone {
+            println!("backend {} removed", self.backends[id].addr);
+            self.remove(id);
+        }
+
+        for backend in backends {
+            let existing = self
+                .backends
+                .iter_mut()
+                .find(|(_, b)| !b.removed && b.addr == backend.addr);
+
+            match existing {
+                Some((_, existing)) => existing.weight = backend.weight,
+                None => {
+                    println!("backend {} added", backend.addr);
+                    self.add(backend.addr, backend.weight);
+                }
+            }
+        }
+
+        // Weights may have changed without adding or removing anything.
+        self.strategy.update(&self.backends);
+    }
+
+    // Record the result of a health check or connection attempt.
+    // The backend is marked up or down only after enough consecutive results.
+    pub fn report(&mut self, id: BackendId, healthy: bool) {
+        let backend = match self.backends.get_mut(id) {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        if healthy {
+            backend.successes = backend.successes.saturating_add(1);
+            backend.failures = 0;
+        } else {
+            backend.failures = backend.failures.saturating_add(1);
+            backend.successes = 0;
+        }
+
+        let up = if backend.up {
+            backend.failures < self.fall
+        } else {
+            backend.successes >= self.rise
+        };
+
+        if up == backend.up {
+            return;
+        }
+
+        backend.up = up;
+        println!(
+            "backend {} is {}",
+            backend.addr,
+            if up { "up" } else { "down" }
+        );
+
+        self.strategy.update(&self.backends);
+    }
+
+    pub fn get(&self, id: BackendId) -> Option<&Backend> {
+        self.backends.get(id)
+    }
+
+    // Iterate over the backends, skipping those removed but still waiting for connections to finish.
+    pub fn iter(&self) -> impl Iterator<Item = (BackendId, &Backend)> {
+        self.backends.iter().filter(|(_, backend)| !backend.removed)
+    }
+
+    // Pick a backend for a new connection from the client, preferring any not excluded.
+    // The connection counts against the backend until it's released.
+    pub fn acquire(
+        &mut self,
+        client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<(BackendId, net::SocketAddr)> {
+        let id = match self.strategy.pick(&self.backends, client, exclude) {
+            Some(id) => id,
+            None if !exclude.is_empty() => self.strategy.pick(&self.backends, client, &[])?,
+            None => return None,
+        };
+
+        let backend = &mut self.backends[id];
+        backend.active += 1;
+
+        Some((id, backend.addr))
+    }
+
+    pub fn release(&mut self, id: BackendId) {
+        let backend = match self.backends.get_mut(id) {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        backend.active -= 1;
+
+        if backend.removed && backend.active == 0 {
+            self.backends.remove(id);
+        }
+    }
+}
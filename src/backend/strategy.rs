@@ -0,0 +1,356 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::net;
+
+use enum_dispatch::enum_dispatch;
+use rand::Rng;
+use slab::Slab;
+
+use super::{Backend, BackendId};
+
+#[enum_dispatch]
+pub trait Strategy {
+    // Called whenever backends are added or removed.
+    fn update(&mut self, _backends: &Slab<Backend>) {}
+
+    // Pick an available backend for a new connection from the client, skipping any excluded.
+    fn pick(
+        &mut self,
+        backends: &Slab<Backend>,
+        client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<BackendId>;
+}
+
+#[enum_dispatch(Strategy)]
+pub enum StrategyType {
+    RoundRobin,
+    LeastConnections,
+    RandomTwo,
+    ConsistentHash,
+}
+
+// Iterate over the backends that can be picked.
+fn candidates<'a>(
+    backends: &'a Slab<Backend>,
+    exclude: &'a [BackendId],
+) -> impl Iterator<Item = (BackendId, &'a Backend)> {
+    backends
+        .iter()
+        .filter(move |(id, backend)| backend.available() && !exclude.contains(id))
+}
+
+// Smooth weighted round-robin, which interleaves heavier backends rather than bursting them.
+#[derive(Default)]
+pub struct RoundRobin {
+    current: Vec<i64>, // indexed by backend id
+}
+
+impl Strategy for RoundRobin {
+    fn update(&mut self, backends: &Slab<Backend>) {
+        self.current.clear();
+        self.current.resize(backends.capacity(), 0);
+    }
+
+    fn pick(
+        &mut self,
+        backends: &Slab<Backend>,
+        _client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<BackendId> {
+        let mut total = 0;
+        let mut best: Option<BackendId> = None;
+
+        for (id, backend) in candidates(backends, exclude) {
+            if id >= self.current.len() {
+                self.current.resize(id + 1, 0);
+            }
+
+            self.current[id] += backend.weight as i64;
+            total += backend.weight as i64;
+
+            match best {
+                Some(best_id) if self.current[best_id] >= self.current[id] => {}
+                _ => best = Some(id),
+            }
+        }
+
+        let id = best?;
+        self.current[id] -= total;
+
+        Some(id)
+    }
+}
+
+// Pick the backend with the fewest connections relative to its weight.
+#[derive(Default)]
+pub struct LeastConnections {}
+
+impl Strategy for LeastConnections {
+    fn pick(
+        &mut self,
+        backends: &Slab<Backend>,
+        _client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<BackendId> {
+        let mut best: Option<(BackendId, &Backend)> = None;
+
+        for (id, backend) in candidates(backends, exclude) {
+            match best {
+                Some((_, best_backend)) if !backend.less_loaded(best_backend) => {}
+                _ => best = Some((id, backend)),
+            }
+        }
+
+        best.map(|(id, _)| id)
+    }
+}
+
+// Pick two backends at random by weight and use the one with fewer connections.
+#[derive(Default)]
+pub struct RandomTwo {}
+
+impl RandomTwo {
+    fn random(backends: &Slab<Backend>, exclude: &[BackendId], total: u64) -> Option<BackendId> {
+        let mut n = rand::thread_rng().gen_range(0..total);
+
+        for (id, backend) in candidates(backends, exclude) {
+            let weight = backend.weight as u64;
+            if n < weight {
+                return Some(id);
+            }
+
+            n -= weight;
+        }
+
+        None
+    }
+}
+
+impl Strategy for RandomTwo {
+    fn pick(
+        &mut self,
+        backends: &Slab<Backend>,
+        _client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<BackendId> {
+        let total: u64 = candidates(backends, exclude)
+            .map(|(_, b)| b.weight as u64)
+            .sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        let a = Self::random(backends, exclude, total)?;
+        let b = Self::random(backends, exclude, total)?;
+
+        if backends[b].less_loaded(&backends[a]) {
+            Some(b)
+        } else {
+            Some(a)
+        }
+    }
+}
+
+// Hash the client IP onto a ring of backends, so a client sticks to the same backend
+// and only a fraction of clients move when a backend joins or leaves.
+#[derive(Default)]
+pub struct ConsistentHash {
+    ring: BTreeMap<u64, BackendId>,
+}
+
+impl ConsistentHash {
+    // Number of points on the ring per unit of weight.
+    const POINTS: u64 = 100;
+
+    fn hash<T: Hash>(value: T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Strategy for ConsistentHash {
+    fn update(&mut self, backends: &Slab<Backend>) {
+        self.ring.clear();
+
+        for (id, backend) in backends.iter().filter(|(_, b)| b.available()) {
+            for point in 0..u64::from(backend.weight) * Self::POINTS {
+                self.ring.insert(Self::hash((backend.addr, point)), id);
+            }
+        }
+    }
+
+    fn pick(
+        &mut self,
+        backends: &Slab<Backend>,
+        client: &net::IpAddr,
+        exclude: &[BackendId],
+    ) -> Option<BackendId> {
+        let hash = Self::hash(client);
+
+        // Walk clockwise from the client's position until we find an available backend.
+        self.ring
+            .range(hash..)
+            .chain(self.ring.range(..hash))
+            .map(|(_, id)| *id)
+            .find(|id| backends[*id].available() && !exclude.contains(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(weights: &[u32]) -> Slab<Backend> {
+        let mut backends = Slab::new();
+        for (i, weight) in weights.iter().enumerate() {
+            let addr = net::SocketAddr::from(([10, 0, 0, i as u8], 80));
+            backends.insert(Backend::new(addr, *weight));
+        }
+
+        backends
+    }
+
+    fn client(i: u32) -> net::IpAddr {
+        net::Ipv4Addr::from(0xc0a8_0000 + i).into()
+    }
+
+    // Pick for each client in turn, returning the backends picked.
+    fn picks<S: Strategy>(strategy: &mut S, backends: &Slab<Backend>, n: u32) -> Vec<BackendId> {
+        (0..n)
+            .map(|i| strategy.pick(backends, &client(i), &[]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_weights() {
+        let backends = backends(&[5, 1, 1]);
+        let mut strategy = RoundRobin::default();
+        strategy.update(&backends);
+
+        // Heavier backends are spread through the round instead of picked back to back.
+        let round = vec![0, 0, 1, 0, 2, 0, 0];
+        assert_eq!(picks(&mut strategy, &backends, 7), round);
+        assert_eq!(picks(&mut strategy, &backends, 7), round);
+    }
+
+    #[test]
+    fn round_robin_exclude() {
+        let mut backends = backends(&[1, 1, 1]);
+        backends[1].up = false;
+
+        let mut strategy = RoundRobin::default();
+        strategy.update(&backends);
+
+        for _ in 0..6 {
+            let id = strategy.pick(&backends, &client(0), &[2]).unwrap();
+            assert_eq!(id, 0);
+        }
+
+        assert_eq!(strategy.pick(&backends, &client(0), &[0, 2]), None);
+    }
+
+    #[test]
+    fn least_connections() {
+        let mut backends = backends(&[1, 2, 1]);
+        backends[0].active = 2;
+        backends[1].active = 3;
+        backends[2].active = 1;
+
+        let mut strategy = LeastConnections::default();
+
+        // Relative to weight, the second backend has fewer connections than the first.
+        assert_eq!(strategy.pick(&backends, &client(0), &[]), Some(2));
+        assert_eq!(strategy.pick(&backends, &client(0), &[2]), Some(1));
+
+        backends[2].active = 2;
+        assert_eq!(strategy.pick(&backends, &client(0), &[]), Some(1));
+
+        backends[1].removed = true;
+        assert_eq!(strategy.pick(&backends, &client(0), &[]), Some(0));
+    }
+
+    #[test]
+    fn random_two() {
+        let mut backends = backends(&[1, 3]);
+        let mut strategy = RandomTwo::default();
+
+        // With no connections, picks follow the weights.
+        let heavy = picks(&mut strategy, &backends, 4000)
+            .into_iter()
+            .filter(|id| *id == 1)
+            .count();
+        assert!((2600..3400).contains(&heavy), "{}", heavy);
+
+        // The less loaded of the two is used, so a busy backend is only picked if it's drawn twice.
+        backends[1].active = 100;
+        let heavy = picks(&mut strategy, &backends, 4000)
+            .into_iter()
+            .filter(|id| *id == 1)
+            .count();
+        assert!((2000..2600).contains(&heavy), "{}", heavy);
+
+        assert_eq!(strategy.pick(&backends, &client(0), &[1]), Some(0));
+        assert_eq!(strategy.pick(&backends, &client(0), &[0, 1]), None);
+    }
+
+    #[test]
+    fn consistent_hash_sticky() {
+        let backends = backends(&[1, 1, 1]);
+        let mut strategy = ConsistentHash::default();
+        strategy.update(&backends);
+
+        let first = picks(&mut strategy, &backends, 1000);
+        assert_eq!(picks(&mut strategy, &backends, 1000), first);
+
+        // Every backend gets a share of the clients.
+        for id in 0..3 {
+            let count = first.iter().filter(|picked| **picked == id).count();
+            assert!(count > 150, "backend {} got {} clients", id, count);
+        }
+
+        // An excluded backend's clients move on, while the rest stay put.
+        for i in 0..1000 {
+            let id = strategy.pick(&backends, &client(i), &[0]).unwrap();
+            assert_ne!(id, 0);
+            if first[i as usize] != 0 {
+                assert_eq!(id, first[i as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_remap() {
+        let mut backends = backends(&[1, 1, 1]);
+        let mut strategy = ConsistentHash::default();
+        strategy.update(&backends);
+
+        let before = picks(&mut strategy, &backends, 1000);
+
+        // Only the clients of a backend that leaves are moved.
+        backends[1].removed = true;
+        strategy.update(&backends);
+
+        let after = picks(&mut strategy, &backends, 1000);
+        for (before, after) in before.iter().zip(&after) {
+            assert_ne!(*after, 1);
+            if *before != 1 {
+                assert_eq!(before, after);
+            }
+        }
+
+        // A backend that joins only takes clients, rather than shuffling the rest.
+        backends.insert(Backend::new(net::SocketAddr::from(([10, 0, 0, 9], 80)), 1));
+        strategy.update(&backends);
+
+        let joined = picks(&mut strategy, &backends, 1000);
+        let moved = after.iter().zip(&joined).filter(|(a, b)| a != b).count();
+
+        assert!(joined.iter().all(|id| *id == 3 || after.contains(id)));
+        assert!(moved > 0 && moved < 700, "{} clients moved", moved);
+        assert!(after.iter().zip(&joined).all(|(a, b)| a == b || *b == 3));
+    }
+}
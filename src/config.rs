@@ -0,0 +1,800 @@
+use std::convert::TryFrom;
+use std::{fmt, fs, net, path, str, thread, time};
+
+use anyhow::Context;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+// Command line arguments, which override any values in the config file.
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "wisp", about = "An io_uring TCP proxy.")]
+pub struct Args {
+    /// Path to a TOML config file.
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<path::PathBuf>,
+
+    /// Address to listen on; may be repeated.
+    #[structopt(short, long)]
+    pub listen: Vec<net::SocketAddr>,
+
+    /// Address of a backend to proxy to, with an optional weight (ex. 10.0.0.1:80@2); may be repeated.
+    #[structopt(short, long)]
+    pub backend: Vec<Backend>,
+
+    /// Load balancing strategy: round-robin, least-connections, random-two or consistent-hash.
+    #[structopt(long)]
+    pub balance: Option<Balance>,
+
+    /// Proxy mode: tcp, or http to respond with a 502 when no backend is reachable.
+    #[structopt(long)]
+    pub mode: Option<Mode>,
+
+    /// Total attempts to connect to a backend for each client, including the first.
+    #[structopt(long)]
+    pub connect_attempts: Option<u32>,
+
+    /// Milliseconds between active health checks of each backend, or 0 to disable them.
+    #[structopt(long)]
+    pub health_interval_ms: Option<u64>,
+
+    /// Check backend health with an HTTP GET of this path instead of only connecting.
+    #[structopt(long)]
+    pub health_path: Option<String>,
+
+    /// Only accept IPv6 connections on IPv6 listeners.
+    #[structopt(long, overrides_with = "no-v6-only")]
+    pub v6_only: bool,
+
+    /// Accept IPv4 connections on IPv6 listeners too.
+    #[structopt(long, overrides_with = "v6-only")]
+    pub no_v6_only: bool,
+
+    /// Unix socket used to hand the listeners over to a new process during an upgrade.
+    #[structopt(long, parse(from_os_str))]
+    pub handoff: Option<path::PathBuf>,
+
+    /// Milliseconds to wait for a backend connection, or 0 to wait forever.
+    #[structopt(long)]
+    pub connect_timeout_ms: Option<u64>,
+
+    /// Milliseconds without data in either direction before closing, or 0 to wait forever.
+    #[structopt(long)]
+    pub idle_timeout_ms: Option<u64>,
+
+    /// Milliseconds a write can stall before the connection is closed, or 0 to wait forever.
+    #[structopt(long)]
+    pub write_timeout_ms: Option<u64>,
+
+    /// Milliseconds to let connections finish after SIGTERM or SIGINT, or 0 to wait forever.
+    #[structopt(long)]
+    pub drain_timeout_ms: Option<u64>,
+
+    /// Number of submission queue entries in the ring.
+    #[structopt(long)]
+    pub ring_entries: Option<u32>,
+
+    /// Number of sockets to register with the ring to skip descriptor lookups, or 0 for none.
+    #[structopt(long)]
+    pub ring_files: Option<u32>,
+
+    /// Poll for submissions with a kernel thread, so submitting skips the syscall.
+    #[structopt(long, overrides_with = "no-ring-sqpoll")]
+    pub ring_sqpoll: bool,
+
+    /// Submit with a syscall instead of a polling thread.
+    #[structopt(long, overrides_with = "ring-sqpoll")]
+    pub no_ring_sqpoll: bool,
+
+    /// Number of completion queue entries in the ring, or 0 for twice the submission entries.
+    #[structopt(long)]
+    pub ring_completions: Option<u32>,
+
+    /// Milliseconds the polling thread waits for submissions before sleeping.
+    #[structopt(long)]
+    pub ring_sqpoll_idle_ms: Option<u32>,
+
+    /// Pin the polling thread to this CPU; requires --ring-sqpoll.
+    #[structopt(long)]
+    pub ring_sqpoll_cpu: Option<u32>,
+
+    /// Run completion work only when entering the kernel, instead of interrupting the worker.
+    #[structopt(long, overrides_with = "no-ring-coop-taskrun")]
+    pub ring_coop_taskrun: bool,
+
+    /// Let completion work interrupt the worker.
+    #[structopt(long, overrides_with = "ring-coop-taskrun")]
+    pub no_ring_coop_taskrun: bool,
+
+    /// Tell the kernel only the worker that created a ring submits to it.
+    #[structopt(long, overrides_with = "no-ring-single-issuer")]
+    pub ring_single_issuer: bool,
+
+    /// Allow any thread to submit to a ring.
+    #[structopt(long, overrides_with = "ring-single-issuer")]
+    pub no_ring_single_issuer: bool,
+
+    /// Run completion work only while waiting for completions; requires --ring-single-issuer.
+    #[structopt(long, overrides_with = "no-ring-defer-taskrun")]
+    pub ring_defer_taskrun: bool,
+
+    /// Run completion work as soon as it's ready.
+    #[structopt(long, overrides_with = "ring-defer-taskrun")]
+    pub no_ring_defer_taskrun: bool,
+
+    /// Share one set of async and polling threads between the workers.
+    #[structopt(long, overrides_with = "no-ring-share")]
+    pub ring_share: bool,
+
+    /// Give each worker its own async and polling threads.
+    #[structopt(long, overrides_with = "ring-share")]
+    pub no_ring_share: bool,
+
+    /// Number of fixed buffers to register with the ring.
+    #[structopt(long)]
+    pub buffer_count: Option<usize>,
+
+    /// Size in bytes of each fixed buffer.
+    #[structopt(long)]
+    pub buffer_size: Option<usize>,
+
+    /// How connections read data: provided to let the kernel pick a buffer, multishot to also keep
+    /// one read armed per socket, fixed to take one from the registered pool, slice for one per
+    /// read, or splice to skip userspace entirely.
+    #[structopt(long)]
+    pub buffer_mode: Option<BufferMode>,
+
+    /// Send writes of at least this many bytes without copying, or 0 to always copy.
+    /// Zero-copy sends only pay off for large writes, and don't apply to slices.
+    #[structopt(long)]
+    pub buffer_zerocopy: Option<usize>,
+
+    /// Number of worker threads, each with its own ring, or 0 for one per CPU.
+    #[structopt(long)]
+    pub workers: Option<usize>,
+
+    /// Pin each worker thread to its own CPU.
+    #[structopt(long, overrides_with = "no-pin-workers")]
+    pub pin_workers: bool,
+
+    /// Let worker threads run on any CPU.
+    #[structopt(long, overrides_with = "pin-workers")]
+    pub no_pin_workers: bool,
+
+    /// Steer each connection to the worker on the CPU that received it; requires --pin-workers.
+    #[structopt(long, overrides_with = "no-steer")]
+    pub steer: bool,
+
+    /// Leave each connection on the worker that accepted it.
+    #[structopt(long, overrides_with = "steer")]
+    pub no_steer: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<net::SocketAddr>,
+    pub backend: Vec<Backend>,
+    pub balance: Balance,
+    pub mode: Mode,
+
+    // IPv6 listeners accept IPv4 connections too unless this is set.
+    pub v6_only: bool,
+
+    // Listeners are taken over from the process listening on this Unix socket, if any.
+    pub handoff: Option<path::PathBuf>,
+
+    // The file to read the backends from again on SIGHUP, unless they were given on the command
+    // line.
+    #[serde(skip)]
+    pub reload: Option<path::PathBuf>,
+
+    pub health: Health,
+    pub retry: Retry,
+    pub timeout: Timeout,
+    pub ring: Ring,
+    pub buffers: Buffers,
+    pub workers: Workers,
+}
+
+impl Config {
+    // Parse the command line, load the config file if provided, and apply any overrides.
+    pub fn from_args() -> anyhow::Result<Self> {
+        Self::load(Args::from_args())
+    }
+
+    pub fn load(args: Args) -> anyhow::Result<Self> {
+        let mut config = match args.config {
+            Some(ref path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+
+        if !args.backend.is_empty() {
+            config.backend = args.backend;
+        } else {
+            config.reload = args.config;
+        }
+
+        if let Some(balance) = args.balance {
+            config.balance = balance;
+        }
+
+        if let Some(mode) = args.mode {
+            config.mode = mode;
+        }
+
+        if let Some(attempts) = args.connect_attempts {
+            config.retry.attempts = attempts;
+        }
+
+        if let Some(ms) = args.health_interval_ms {
+            config.health.interval_ms = ms;
+        }
+
+        if let Some(path) = args.health_path {
+            config.health.path = Some(path);
+        }
+
+        if let Some(v6_only) = switch(args.v6_only, args.no_v6_only) {
+            config.v6_only = v6_only;
+        }
+
+        if let Some(path) = args.handoff {
+            config.handoff = Some(path);
+        }
+
+        if let Some(ms) = args.connect_timeout_ms {
+            config.timeout.connect_ms = ms;
+        }
+
+        if let Some(ms) = args.idle_timeout_ms {
+            config.timeout.idle_ms = ms;
+        }
+
+        if let Some(ms) = args.write_timeout_ms {
+            config.timeout.write_ms = ms;
+        }
+
+        if let Some(ms) = args.drain_timeout_ms {
+            config.timeout.drain_ms = ms;
+        }
+
+        if let Some(entries) = args.ring_entries {
+            config.ring.entries = entries;
+        }
+
+        if let Some(files) = args.ring_files {
+            config.ring.files = files;
+        }
+
+        if let Some(sqpoll) = switch(args.ring_sqpoll, args.no_ring_sqpoll) {
+            config.ring.sqpoll = sqpoll;
+        }
+
+        if let Some(completions) = args.ring_completions {
+            config.ring.completions = completions;
+        }
+
+        if let Some(ms) = args.ring_sqpoll_idle_ms {
+            config.ring.sqpoll_idle_ms = ms;
+        }
+
+        if let Some(cpu) = args.ring_sqpoll_cpu {
+            config.ring.sqpoll_cpu = Some(cpu);
+        }
+
+        if let Some(coop) = switch(args.ring_coop_taskrun, args.no_ring_coop_taskrun) {
+            config.ring.coop_taskrun = coop;
+        }
+
+        if let Some(single) = switch(args.ring_single_issuer, args.no_ring_single_issuer) {
+            config.ring.single_issuer = single;
+        }
+
+        if let Some(defer) = switch(args.ring_defer_taskrun, args.no_ring_defer_taskrun) {
+            config.ring.defer_taskrun = defer;
+        }
+
+        if let Some(share) = switch(args.ring_share, args.no_ring_share) {
+            config.ring.share = share;
+        }
+
+        if let Some(count) = args.buffer_count {
+            config.buffers.count = count;
+        }
+
+        if let Some(size) = args.buffer_size {
+            config.buffers.size = size;
+        }
+
+        if let Some(mode) = args.buffer_mode {
+            config.buffers.mode = mode;
+        }
+
+        if let Some(zerocopy) = args.buffer_zerocopy {
+            config.buffers.zerocopy = zerocopy;
+        }
+
+        if let Some(count) = args.workers {
+            config.workers.count = count;
+        }
+
+        if let Some(pin) = switch(args.pin_workers, args.no_pin_workers) {
+            config.workers.pin = pin;
+        }
+
+        if let Some(steer) = switch(args.steer, args.no_steer) {
+            config.workers.steer = steer;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<path::Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config: {}", path.display()))?;
+
+        Self::parse(&contents).with_context(|| format!("invalid config: {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.listen.is_empty() {
+            anyhow::bail!("no listen addresses");
+        }
+
+        check_backends(&self.backend)?;
+
+        if self.health.rise == 0 || self.health.fall == 0 {
+            anyhow::bail!("health rise and fall must be non-zero");
+        }
+
+        if self.retry.attempts == 0 {
+            anyhow::bail!("retry attempts must be non-zero");
+        }
+
+        if self.ring.entries == 0 {
+            anyhow::bail!("ring entries must be non-zero");
+        }
+
+        if self.ring.completions != 0 && self.ring.completions < self.ring.entries {
+            anyhow::bail!("ring completions must be at least the entries");
+        }
+
+        if self.ring.sqpoll_cpu.is_some() && !self.ring.sqpoll {
+            anyhow::bail!("pinning the polling thread requires sqpoll");
+        }
+
+        // The kernel rejects these, but with a vague EINVAL.
+        if self.ring.defer_taskrun && !self.ring.single_issuer {
+            anyhow::bail!("deferring task work requires a single issuer");
+        }
+
+        if self.ring.defer_taskrun && self.ring.sqpoll {
+            anyhow::bail!("deferring task work doesn't work with sqpoll");
+        }
+
+        if self.buffers.size == 0 {
+            anyhow::bail!("buffer size must be non-zero");
+        }
+
+        // The kernel identifies provided buffers with 16 bits.
+        let provided = matches!(
+            self.buffers.mode,
+            BufferMode::Provided | BufferMode::Multishot | BufferMode::Splice
+        );
+        if provided && self.buffers.count > 1 << 16 {
+            anyhow::bail!("too many buffers to provide");
+        }
+
+        if self.workers.steer && !self.workers.pin {
+            anyhow::bail!("steering connections requires pinned workers");
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![net::SocketAddr::from(([127, 0, 0, 1], 8080))],
+            backend: vec![net::SocketAddr::from(([127, 0, 0, 1], 9001)).into()],
+            balance: Balance::default(),
+            mode: Mode::default(),
+            v6_only: false,
+            handoff: None,
+            reload: None,
+            health: Health::default(),
+            retry: Retry::default(),
+            timeout: Timeout::default(),
+            ring: Ring::default(),
+            buffers: Buffers::default(),
+            workers: Workers::default(),
+        }
+    }
+}
+
+// A backend address, written as either "addr", "addr@weight" or { addr, weight }.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "BackendValue")]
+pub struct Backend {
+    pub addr: net::SocketAddr,
+    pub weight: u32,
+}
+
+impl Backend {
+    pub const MAX_WEIGHT: u32 = 1000;
+}
+
+impl From<net::SocketAddr> for Backend {
+    fn from(addr: net::SocketAddr) -> Self {
+        Self { addr, weight: 1 }
+    }
+}
+
+impl str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, weight) = match s.rfind('@') {
+            Some(index) => (&s[..index], s[index + 1..].parse()?),
+            None => (s, 1),
+        };
+
+        Ok(Self {
+            addr: addr.parse()?,
+            weight,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendValue {
+    Short(String),
+    Full {
+        addr: net::SocketAddr,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+impl TryFrom<BackendValue> for Backend {
+    type Error = anyhow::Error;
+
+    fn try_from(value: BackendValue) -> anyhow::Result<Self> {
+        match value {
+            BackendValue::Short(s) => s.parse(),
+            BackendValue::Full { addr, weight } => Ok(Self { addr, weight }),
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// Read the backends from the config file again, checking them as they would be on startup.
+pub fn reload_backends(path: &path::Path) -> anyhow::Result<Vec<Backend>> {
+    let config = Config::from_file(path)?;
+    check_backends(&config.backend)?;
+
+    Ok(config.backend)
+}
+
+fn check_backends(backends: &[Backend]) -> anyhow::Result<()> {
+    if backends.is_empty() {
+        anyhow::bail!("no backend addresses");
+    }
+
+    // Each unit of weight is another 100 points on the consistent hash ring.
+    if let Some(backend) = backends
+        .iter()
+        .find(|backend| backend.weight == 0 || backend.weight > Backend::MAX_WEIGHT)
+    {
+        anyhow::bail!(
+            "backend weight must be from 1 to {}: {}",
+            Backend::MAX_WEIGHT,
+            backend.addr
+        );
+    }
+
+    Ok(())
+}
+
+// How to pick a backend for each new connection.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    RandomTwo,
+    ConsistentHash,
+}
+
+impl str::FromStr for Balance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "round-robin" => Self::RoundRobin,
+            "least-connections" => Self::LeastConnections,
+            "random-two" => Self::RandomTwo,
+            "consistent-hash" => Self::ConsistentHash,
+            _ => anyhow::bail!("unknown balance strategy: {}", s),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Tcp,
+    Http, // clients get a 502 if a backend can't be reached
+}
+
+impl str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "tcp" => Self::Tcp,
+            "http" => Self::Http,
+            _ => anyhow::bail!("unknown mode: {}", s),
+        })
+    }
+}
+
+// Active health checks against each backend.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    pub interval_ms: u64, // time between checks, or 0 to disable them
+    pub timeout_ms: u64,  // time allowed for each step of a check
+    pub rise: u32,        // consecutive successes before a backend is marked up
+    pub fall: u32,        // consecutive failures before a backend is marked down
+
+    // Send an HTTP GET for this path and expect the status, instead of only connecting.
+    pub path: Option<String>,
+    pub status: u16,
+}
+
+impl Health {
+    pub fn interval(&self) -> Option<time::Duration> {
+        millis(self.interval_ms)
+    }
+
+    pub fn timeout(&self) -> Option<time::Duration> {
+        millis(self.timeout_ms)
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            interval_ms: 5_000,
+            timeout_ms: 1_000,
+            rise: 2,
+            fall: 3,
+            path: None,
+            status: 200,
+        }
+    }
+}
+
+// Retrying failed backend connections, failing over to other backends when possible.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retry {
+    pub attempts: u32,    // total attempts per client, including the first
+    pub backoff_ms: u64,  // delay before the first retry, doubling after each failure
+    pub deadline_ms: u64, // total time allowed to connect, or 0 for no limit
+}
+
+impl Retry {
+    pub fn backoff(&self) -> time::Duration {
+        time::Duration::from_millis(self.backoff_ms)
+    }
+
+    pub fn deadline(&self) -> Option<time::Duration> {
+        millis(self.deadline_ms)
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 50,
+            deadline_ms: 10_000,
+        }
+    }
+}
+
+// Per-connection timeouts in milliseconds; 0 disables the timeout.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeout {
+    pub connect_ms: u64, // dialing the backend
+    pub idle_ms: u64,    // no data moving in either direction
+    pub write_ms: u64,   // waiting for the peer to accept data
+    pub drain_ms: u64,   // finishing in-flight connections on shutdown
+}
+
+impl Timeout {
+    pub fn connect(&self) -> Option<time::Duration> {
+        millis(self.connect_ms)
+    }
+
+    pub fn idle(&self) -> Option<time::Duration> {
+        millis(self.idle_ms)
+    }
+
+    pub fn write(&self) -> Option<time::Duration> {
+        millis(self.write_ms)
+    }
+
+    pub fn drain(&self) -> Option<time::Duration> {
+        millis(self.drain_ms)
+    }
+}
+
+impl Default for Timeout {
+    fn default() -> Self {
+        Self {
+            connect_ms: 5_000,
+            idle_ms: 60_000,
+            write_ms: 30_000,
+            drain_ms: 30_000,
+        }
+    }
+}
+
+// A switch from the command line, given as --flag or --no-flag to override the config file either
+// way. The last one given wins, so at most one is set.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn millis(ms: u64) -> Option<time::Duration> {
+    match ms {
+        0 => None,
+        ms => Some(time::Duration::from_millis(ms)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ring {
+    pub entries: u32,
+    pub files: u32,              // sockets to register, or 0 for none
+    pub completions: u32,        // or 0 for twice the entries
+    pub sqpoll: bool,            // a kernel thread polls for tasks to submit
+    pub sqpoll_idle_ms: u32,     // how long it polls for nothing before sleeping
+    pub sqpoll_cpu: Option<u32>, // pin the polling thread to this CPU
+    pub coop_taskrun: bool,      // run completion work when entering the kernel
+    pub single_issuer: bool,     // only the thread that created a ring submits
+    pub defer_taskrun: bool,     // run completion work only while waiting
+    pub share: bool,             // workers share async and polling threads
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self {
+            entries: 1024,
+            files: 4096,
+            completions: 0,
+            sqpoll: false,
+            sqpoll_idle_ms: 1000,
+            sqpoll_cpu: None,
+            coop_taskrun: false,
+            single_issuer: false,
+            defer_taskrun: false,
+            share: false,
+        }
+    }
+}
+
+// Fixed buffers registered with the ring.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buffers {
+    pub count: usize,
+    pub size: usize,
+    pub mode: BufferMode,
+    pub zerocopy: usize, // the smallest write that's sent without copying, or 0 for none
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            count: 1024,
+            size: 4096,
+            mode: BufferMode::default(),
+            zerocopy: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BufferMode {
+    Slice, // each read allocates its own buffer, held while waiting for data
+    #[default]
+    Provided, // the kernel picks a buffer once data arrives, so idle reads hold no memory
+    Multishot, // like provided, but one read stays armed per socket instead of one per chunk
+    Fixed, // each read takes a registered buffer from the pool, pausing when it runs out
+    Splice, // data moves through a kernel pipe without being copied, or provided if unsupported
+}
+
+impl str::FromStr for BufferMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "slice" => Self::Slice,
+            "provided" => Self::Provided,
+            "multishot" => Self::Multishot,
+            "fixed" => Self::Fixed,
+            "splice" => Self::Splice,
+            _ => anyhow::bail!("unknown buffer mode: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for BufferMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Slice => "slice",
+            Self::Provided => "provided",
+            Self::Multishot => "multishot",
+            Self::Fixed => "fixed",
+            Self::Splice => "splice",
+        })
+    }
+}
+
+// Worker threads that each run their own ring and share the listen ports with SO_REUSEPORT.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workers {
+    pub count: usize, // 0 for one per CPU
+    pub pin: bool,    // pin worker N to the Nth CPU
+    pub steer: bool,  // hand connections to the worker on the CPU that received them
+}
+
+impl Workers {
+    pub fn count(&self) -> usize {
+        match self.count {
+            0 => thread::available_parallelism().map_or(1, |count| count.get()),
+            count => count,
+        }
+    }
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            pin: false,
+            steer: false,
+        }
+    }
+}
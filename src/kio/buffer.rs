@@ -1,5 +1,11 @@
+use std::alloc::{self, Layout};
 use std::collections::LinkedList;
-use std::ops;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::{io, mem, ops, ptr};
+
+// The group that buffers are provided to the kernel in.
+pub const PROVIDED_GROUP: u16 = 0;
 
 pub struct Slice {
     data: Box<[u8]>,
@@ -59,6 +65,42 @@ impl ops::DerefMut for Fixed {
     }
 }
 
+// A buffer of either kind, for tasks that take both.
+pub enum Buffer {
+    Slice(Slice), // allocated by the caller
+    Fixed(Fixed), // registered with the ring
+}
+
+impl Buffer {
+    // The index in the ring's registered buffers, if registered.
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            Buffer::Slice(_) => None,
+            Buffer::Fixed(buffer) => Some(buffer.id()),
+        }
+    }
+}
+
+impl ops::Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Buffer::Slice(buffer) => buffer,
+            Buffer::Fixed(buffer) => buffer,
+        }
+    }
+}
+
+impl ops::DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Buffer::Slice(buffer) => buffer,
+            Buffer::Fixed(buffer) => buffer,
+        }
+    }
+}
+
 pub struct Pool {
     buffers: LinkedList<Fixed>,
 }
@@ -75,6 +117,7 @@ impl Pool {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Pool {
     fn default() -> Self {
         Self {
@@ -82,3 +125,143 @@ impl Default for Pool {
         }
     }
 }
+
+// An entry in a buffer ring, matching the kernel's io_uring_buf.
+#[repr(C)]
+struct RingEntry {
+    addr: u64,
+    len: u32,
+    id: u16,
+    tail: u16, // only used in the first entry, where the kernel looks for the ring's tail
+}
+
+// IORING_REGISTER_PBUF_RING and IORING_UNREGISTER_PBUF_RING, which io_uring doesn't expose yet.
+const REGISTER_PBUF_RING: libc::c_uint = 22;
+const UNREGISTER_PBUF_RING: libc::c_uint = 23;
+
+// The kernel's io_uring_buf_reg, describing a buffer ring to register or unregister.
+#[repr(C)]
+struct RingRegister {
+    addr: u64,
+    entries: u32,
+    group: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+// A ring of buffers shared with the kernel, which picks from it once data arrives.
+// Unlike providing buffers with a task, giving one back is just a write to the shared memory.
+// It's unregistered before being freed, so the io_uring it was registered with must outlive it.
+// NOTE: This only holds the addresses, so the buffers must outlive the ring's registration.
+pub struct Ring {
+    entries: *mut RingEntry,
+    layout: Layout,
+    mask: u16,
+    tail: u16,
+
+    fd: RawFd,  // the io_uring it's registered with
+    group: u16, // the group that reads pick buffers from
+}
+
+impl Ring {
+    // Register a ring with room for size buffers as the group.
+    // The kernel requires a power of two, less than 1 << 16.
+    pub fn register(fd: RawFd, size: u16, group: u16) -> io::Result<Self> {
+        assert!(size.is_power_of_two() && size <= 1 << 15);
+
+        // The kernel maps the ring, so it must be page aligned.
+        let layout = Layout::from_size_align(size as usize * mem::size_of::<RingEntry>(), 4096)
+            .expect("invalid ring layout");
+
+        let entries = unsafe { alloc::alloc_zeroed(layout) } as *mut RingEntry;
+        if entries.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        let register = RingRegister {
+            addr: entries as _,
+            entries: size as _,
+            group,
+            flags: 0,
+            resv: [0; 3],
+        };
+
+        // Only a registered ring is unregistered when dropped, since the group may belong to
+        // another ring if this one failed.
+        if let Err(err) = register_ring(fd, REGISTER_PBUF_RING, &register) {
+            unsafe { alloc::dealloc(entries as _, layout) };
+            return Err(err);
+        }
+
+        Ok(Self {
+            entries,
+            layout,
+            mask: size - 1,
+            tail: 0,
+            fd,
+            group,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.entries as _
+    }
+
+    // The number of entries, which is the most buffers it can hold.
+    pub fn size(&self) -> u16 {
+        self.mask + 1
+    }
+
+    // Add the buffer to the ring, making it visible to the kernel.
+    // NOTE: The ring has room for every buffer it was created for, so the caller mustn't add more.
+    pub fn push(&mut self, buffer: &mut Fixed) {
+        // Write the fields through the pointer, since the first entry also holds the tail.
+        unsafe {
+            let entry = self.entries.add((self.tail & self.mask) as usize);
+            (*entry).addr = buffer.as_mut_ptr() as _;
+            (*entry).len = buffer.len() as _;
+            (*entry).id = buffer.id() as _;
+        }
+
+        self.tail = self.tail.wrapping_add(1);
+
+        // Publish the entry only after it has been written.
+        let tail = unsafe { &*(ptr::addr_of!((*self.entries).tail) as *const AtomicU16) };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // The kernel keeps reading the entries until the ring is unregistered.
+        let unregister = RingRegister {
+            addr: 0,
+            entries: 0,
+            group: self.group,
+            flags: 0,
+            resv: [0; 3],
+        };
+
+        let _ = register_ring(self.fd, UNREGISTER_PBUF_RING, &unregister);
+
+        unsafe { alloc::dealloc(self.entries as _, self.layout) };
+    }
+}
+
+fn register_ring(fd: RawFd, opcode: libc::c_uint, register: &RingRegister) -> io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_io_uring_register,
+            fd,
+            opcode,
+            register as *const RingRegister,
+            1,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
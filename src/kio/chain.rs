@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{io, net, ops, time};
+
+use super::runtime::Runtime;
+use super::task::{self, TaskId, TaskType};
+use super::{buffer, tcp};
+
+// Builds tasks that run one after another, each only starting once the previous one succeeded.
+// The chain is pushed to the ring all at once, so a full queue can never split it.
+// NOTE: A short read or write counts as a failure, cancelling the rest, unless added as partial.
+pub struct Chain<'r, 'a> {
+    runtime: &'r mut Runtime<'a>,
+    tasks: Vec<TaskType>,
+    links: Vec<Link>,
+}
+
+// How a link in the chain finishes and what it means for the rest.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Link {
+    Task,
+    Sleep,   // ends with ETIME, so the next link is hard linked to run anyway
+    Partial, // may move less than asked, so the next link is hard linked to run anyway
+    Timeout, // applies to the task before it
+}
+
+impl<'r, 'a> Chain<'r, 'a> {
+    pub(super) fn new(runtime: &'r mut Runtime<'a>) -> Self {
+        Self {
+            runtime,
+            tasks: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    // Add any task to the end of the chain.
+    pub fn then(self, task: TaskType) -> Self {
+        self.link(task, Link::Task)
+    }
+
+    // Add a task that may move less than asked without stopping the chain, such as a splice into a
+    // pipe that the next link empties.
+    pub fn partial(self, task: TaskType) -> Self {
+        self.link(task, Link::Partial)
+    }
+
+    pub fn connect(self, socket: tcp::Reader, addr: net::SocketAddr) -> Self {
+        self.then(task::Connect::new(socket, addr).into())
+    }
+
+    pub fn read(self, socket: tcp::Reader, buffer: buffer::Slice) -> Self {
+        self.then(task::Read { socket, buffer }.into())
+    }
+
+    pub fn read_fixed(self, socket: tcp::Reader, buffer: buffer::Fixed) -> Self {
+        self.then(task::ReadFixed { socket, buffer }.into())
+    }
+
+    // Wait for the duration before the next link.
+    pub fn sleep(self, duration: time::Duration) -> Self {
+        self.link(task::Sleep::new(duration).into(), Link::Sleep)
+    }
+
+    pub fn write<R>(self, socket: tcp::Writer, buffer: buffer::Slice, range: R) -> Self
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        self.then(task::Write::new(socket, buffer, range).into())
+    }
+
+    pub fn write_fixed<R>(self, socket: tcp::Writer, buffer: buffer::Fixed, range: R) -> Self
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        self.then(task::WriteFixed::new(socket, buffer, range).into())
+    }
+
+    // Cancel the last task added if it hasn't finished within the duration, failing the chain.
+    pub fn timeout(self, duration: time::Duration) -> Self {
+        assert!(
+            matches!(self.links.last(), Some(Link::Task)),
+            "timeout must follow a task"
+        );
+
+        self.link(task::Timeout::new(duration).into(), Link::Timeout)
+    }
+
+    // Run the chain, returning the ids of its tasks to match against their completions.
+    pub fn submit(self) -> Links {
+        self.runtime.run_chain(self.tasks, self.links)
+    }
+
+    fn link(mut self, task: TaskType, link: Link) -> Self {
+        self.tasks.push(task);
+        self.links.push(link);
+        self
+    }
+}
+
+// The tasks of a submitted chain, which learn how each one finished as its completion is taken.
+pub struct Links {
+    ids: Vec<TaskId>,
+    links: Vec<Link>,
+    results: Results,
+}
+
+// The result of each link in a chain, set as it completes.
+type Results = Rc<RefCell<Vec<Option<i32>>>>;
+
+// The link that stopped the chain; the links after it fail with ECANCELED.
+#[derive(Debug)]
+pub struct Failure {
+    pub index: usize, // counting tasks and sleeps, not timeouts
+    pub id: TaskId,
+    pub error: io::Error, // ETIMEDOUT if its timeout fired
+}
+
+impl Links {
+    // The ids of the tasks and sleeps in order, without their timeouts.
+    pub fn ids(&self) -> Vec<TaskId> {
+        self.tasks().collect()
+    }
+
+    // The number of tasks and sleeps, without their timeouts.
+    pub fn len(&self) -> usize {
+        self.tasks().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns true once every link has completed.
+    pub fn is_done(&self) -> bool {
+        self.results.borrow().iter().all(Option::is_some)
+    }
+
+    // Returns the link that failed, once it's known from the completions taken so far.
+    pub fn failed(&self) -> Option<Failure> {
+        let results = self.results.borrow();
+
+        // A timeout firing cancels the task before it.
+        for (link, result) in results.iter().enumerate() {
+            if self.links[link] == Link::Timeout && *result == Some(-libc::ETIME) {
+                let error = io::Error::from_raw_os_error(libc::ETIMEDOUT);
+                return Some(self.failure(link - 1, error));
+            }
+        }
+
+        // Otherwise the first error that isn't a cancel, or a sleep ending.
+        for (link, result) in results.iter().enumerate() {
+            let ret = match (self.links[link], result) {
+                (Link::Timeout, _) | (_, None) => continue,
+                (Link::Sleep, Some(ret)) if *ret == -libc::ETIME => continue,
+                (_, Some(ret)) => -*ret,
+            };
+
+            if ret > 0 && ret != libc::ECANCELED {
+                return Some(self.failure(link, io::Error::from_raw_os_error(ret)));
+            }
+        }
+
+        // A task that was cancelled although everything before it succeeded either followed a
+        // short read or write, or was the first and cancelled by someone else.
+        let (link, _) = results
+            .iter()
+            .enumerate()
+            .find(|(link, result)| self.is_task(*link) && **result == Some(-libc::ECANCELED))?;
+
+        let previous = (0..link).rev().find(|previous| self.is_task(*previous));
+        match previous {
+            Some(previous) => results[previous].map(|ret| {
+                let error = io::Error::other(format!("short transfer of {} bytes", ret));
+                self.failure(previous, error)
+            }),
+            None => Some(self.failure(link, io::Error::from_raw_os_error(libc::ECANCELED))),
+        }
+    }
+
+    fn failure(&self, link: usize, error: io::Error) -> Failure {
+        let index = (0..link).filter(|link| self.is_task(*link)).count();
+
+        Failure {
+            index,
+            id: self.ids[link],
+            error,
+        }
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskId> + '_ {
+        self.ids
+            .iter()
+            .zip(&self.links)
+            .filter(|(_, link)| **link != Link::Timeout)
+            .map(|(id, _)| *id)
+    }
+
+    fn is_task(&self, link: usize) -> bool {
+        self.links[link] != Link::Timeout
+    }
+}
+
+// Tracks the links of submitted chains, recording each result as it completes.
+#[derive(Default)]
+pub struct Table {
+    running: HashMap<TaskId, (Results, usize)>, // by task, its chain and link
+}
+
+impl Table {
+    pub fn insert(&mut self, ids: Vec<TaskId>, links: Vec<Link>) -> Links {
+        let results = Rc::new(RefCell::new(vec![None; ids.len()]));
+
+        for (link, id) in ids.iter().enumerate() {
+            self.running.insert(*id, (results.clone(), link));
+        }
+
+        Links {
+            ids,
+            links,
+            results,
+        }
+    }
+
+    // Called with the result of each task's final completion.
+    pub fn complete(&mut self, id: TaskId, ret: i32) {
+        if let Some((results, link)) = self.running.remove(&id) {
+            results.borrow_mut()[link] = Some(ret);
+        }
+    }
+}
@@ -1,17 +1,20 @@
+use std::convert::TryFrom;
 use std::os::unix::io::FromRawFd;
-use std::{io, net};
+use std::os::unix::net::UnixStream;
+use std::{io, mem, net};
 
 use enum_dispatch::enum_dispatch;
+use nix::sys::{signal, socket};
 
-use super::task;
+use super::{buffer, task, tcp, timer};
 
 pub struct Accept {
-    pub task: task::Accept,
+    pub task: Option<task::Accept>, // returned once a repeated accept stops
     pub socket: Result<net::TcpStream, io::Error>, // the new connection
 }
 
 impl Accept {
-    pub fn new(task: task::Accept, ret: i32) -> Self {
+    pub fn new(task: Option<task::Accept>, ret: i32) -> Self {
         let socket = if ret >= 0 {
             Ok(unsafe { net::TcpStream::from_raw_fd(ret) })
         } else {
@@ -22,6 +25,54 @@ impl Accept {
     }
 }
 
+pub struct AcceptDirect {
+    pub task: task::AcceptDirect,
+    pub socket: Result<(tcp::Reader, tcp::Writer), io::Error>, // the new connection
+    pub peer: Option<net::SocketAddr>,                         // the address it came from
+}
+
+impl AcceptDirect {
+    pub fn new(mut task: task::AcceptDirect, ret: i32) -> Self {
+        // The kernel returns 0 after filling the slot, or the descriptor if there was no slot.
+        let socket = if ret < 0 {
+            Err(io::Error::from_raw_os_error(-ret))
+        } else {
+            match task.slot.take() {
+                Some(slot) => Ok(tcp::split_direct(slot)),
+                None => Ok(tcp::split(unsafe { net::TcpStream::from_raw_fd(ret) })),
+            }
+        };
+
+        let (storage, len) = &*task.addr;
+        let peer = match &socket {
+            Ok(_) => match socket::sockaddr_storage_to_addr(storage, *len as usize) {
+                Ok(socket::SockAddr::Inet(addr)) => Some(addr.to_std()),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+
+        Self { task, socket, peer }
+    }
+}
+
+pub struct AcceptUnix {
+    pub task: task::AcceptUnix,
+    pub socket: Result<UnixStream, io::Error>, // the new connection
+}
+
+impl AcceptUnix {
+    pub fn new(task: task::AcceptUnix, ret: i32) -> Self {
+        let socket = if ret >= 0 {
+            Ok(unsafe { UnixStream::from_raw_fd(ret) })
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, socket }
+    }
+}
+
 pub struct Cancel {
     pub task: task::Cancel,
     pub result: Result<(), io::Error>,
@@ -56,6 +107,63 @@ impl Connect {
     }
 }
 
+pub struct Event {
+    pub task: task::Event,
+    pub count: Result<u64, io::Error>, // number of notifications since the last read
+}
+
+impl Event {
+    pub fn new(task: task::Event, ret: i32) -> Self {
+        let count = if ret >= 0 {
+            Ok(*task.count)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, count }
+    }
+}
+
+// A timer started with Runtime::timer reached its deadline. There's no task, so it's returned
+// with an id that's never a task's.
+pub struct Expired {
+    pub timer: timer::TimerId,
+}
+
+pub struct Poll {
+    pub task: task::Poll,
+    pub events: Result<libc::c_short, io::Error>, // the events that are ready
+}
+
+impl Poll {
+    pub fn new(task: task::Poll, ret: i32) -> Self {
+        let events = if ret >= 0 {
+            Ok(ret as _)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, events }
+    }
+}
+
+pub struct Provide {
+    pub task: task::Provide,
+    pub result: Result<(), io::Error>, // the buffer goes back to the pool on error
+}
+
+impl Provide {
+    pub fn new(task: task::Provide, ret: i32) -> Self {
+        let result = if ret >= 0 {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, result }
+    }
+}
+
 pub struct Read {
     pub task: task::Read,
     pub size: Result<usize, io::Error>, // number of bytes that were read
@@ -90,13 +198,203 @@ impl ReadFixed {
     }
 }
 
+pub struct ReadProvided {
+    pub task: task::ReadProvided,
+    pub size: Result<usize, io::Error>, // number of bytes that were read
+    pub buffer: Option<buffer::Fixed>, // the buffer the kernel picked, which should be provided again
+}
+
+impl ReadProvided {
+    pub fn new(task: task::ReadProvided, ret: i32) -> Self {
+        let size = if ret >= 0 {
+            Ok(ret as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        // Filled in by the runtime, which owns the provided buffers.
+        Self {
+            task,
+            size,
+            buffer: None,
+        }
+    }
+}
+
+pub struct RecvMulti {
+    pub task: Option<task::RecvMulti>, // returned once the receives stop
+    pub size: Result<usize, io::Error>, // number of bytes that were read
+    pub buffer: Option<buffer::Fixed>, // the buffer the kernel picked, which should be provided again
+}
+
+impl RecvMulti {
+    pub fn new(task: Option<task::RecvMulti>, ret: i32) -> Self {
+        let size = if ret >= 0 {
+            Ok(ret as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        // Filled in by the runtime, which owns the provided buffers.
+        Self {
+            task,
+            size,
+            buffer: None,
+        }
+    }
+}
+
+pub struct SendZc {
+    pub task: Option<task::SendZc>, // returned with the buffer once the kernel is done with it
+    pub socket: Option<tcp::Writer>, // returned with the result, which usually comes first
+    pub size: Option<Result<usize, io::Error>>, // number of bytes that were sent, with the socket
+    pub len: usize,                 // number of bytes that were to be sent
+}
+
+impl SendZc {
+    pub fn new(mut task: task::SendZc, ret: i32) -> Self {
+        let len = task.end - task.start;
+
+        // The socket is still here unless the result came first, with a notification following.
+        let socket = task.socket.take();
+        let size = socket.as_ref().map(|_| {
+            if ret >= 0 {
+                Ok(ret as usize)
+            } else {
+                Err(io::Error::from_raw_os_error(-ret))
+            }
+        });
+
+        Self {
+            task: Some(task),
+            socket,
+            size,
+            len,
+        }
+    }
+
+    // The result, with the notification to follow.
+    pub fn sent(task: &mut task::SendZc, ret: i32) -> Self {
+        let size = if ret >= 0 {
+            Ok(ret as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self {
+            task: None,
+            socket: task.socket.take(),
+            size: Some(size),
+            len: task.end - task.start,
+        }
+    }
+}
+
+pub struct Signal {
+    pub task: task::Signal,
+    pub signal: Result<signal::Signal, io::Error>, // the signal that was received
+}
+
+impl Signal {
+    pub fn new(task: task::Signal, ret: i32) -> Self {
+        let signal = if ret < 0 {
+            Err(io::Error::from_raw_os_error(-ret))
+        } else if ret as usize != mem::size_of_val(&*task.info) {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short signal read",
+            ))
+        } else {
+            signal::Signal::try_from(task.info.ssi_signo as i32)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+        };
+
+        Self { task, signal }
+    }
+}
+
+pub struct Sleep {
+    pub task: task::Sleep,
+    pub result: Result<(), io::Error>, // Ok once the duration has elapsed
+    pub elapsed: bool,                 // false if it finished early after its count of completions
+}
+
+impl Sleep {
+    pub fn new(task: task::Sleep, ret: i32) -> Self {
+        let result = if ret >= 0 || ret == -libc::ETIME {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        let elapsed = ret == -libc::ETIME;
+
+        Self {
+            task,
+            result,
+            elapsed,
+        }
+    }
+}
+
+pub struct Splice {
+    pub task: task::Splice,
+    pub size: Result<usize, io::Error>, // number of bytes that were moved
+}
+
+impl Splice {
+    pub fn new(task: task::Splice, ret: i32) -> Self {
+        let size = if ret >= 0 {
+            Ok(ret as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, size }
+    }
+}
+
 pub struct Timeout {
     pub task: task::Timeout,
-    pub result: Result<(), io::Error>,
+    pub result: Result<(), io::Error>, // Ok if the timeout fired, ECANCELED if the task finished first
 }
 
 impl Timeout {
     pub fn new(task: task::Timeout, ret: i32) -> Self {
+        let result = if ret >= 0 || ret == -libc::ETIME {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, result }
+    }
+}
+
+pub struct TimeoutRemove {
+    pub task: task::TimeoutRemove,
+    pub result: Result<(), io::Error>, // ENOENT if the sleep already finished
+}
+
+impl TimeoutRemove {
+    pub fn new(task: task::TimeoutRemove, ret: i32) -> Self {
+        let result = if ret >= 0 {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(-ret))
+        };
+
+        Self { task, result }
+    }
+}
+
+pub struct TimeoutUpdate {
+    pub task: task::TimeoutUpdate,
+    pub result: Result<(), io::Error>, // ENOENT if the sleep already finished
+}
+
+impl TimeoutUpdate {
+    pub fn new(task: task::TimeoutUpdate, ret: i32) -> Self {
         let result = if ret >= 0 {
             Ok(())
         } else {
@@ -144,26 +442,119 @@ impl WriteFixed {
 #[enum_dispatch]
 pub enum CompletionType {
     Accept,
+    AcceptDirect,
+    AcceptUnix,
     Cancel,
     Connect,
+    Event,
+    Expired,
+    Poll,
+    Provide,
     Read,
     ReadFixed,
+    ReadProvided,
+    RecvMulti,
+    SendZc,
+    Signal,
+    Sleep,
+    Splice,
     Timeout,
+    TimeoutRemove,
+    TimeoutUpdate,
     Write,
     WriteFixed,
 }
 
+// Take the specific completion back out, such as when the task type is known, returning the
+// completion unchanged if it's a different type.
+macro_rules! try_from_completion {
+    ($($name:ident),*) => {
+        $(
+            impl TryFrom<CompletionType> for $name {
+                type Error = CompletionType;
+
+                fn try_from(completion: CompletionType) -> Result<Self, CompletionType> {
+                    match completion {
+                        CompletionType::$name(completion) => Ok(completion),
+                        completion => Err(completion),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+try_from_completion!(
+    Accept,
+    AcceptDirect,
+    AcceptUnix,
+    Cancel,
+    Connect,
+    Event,
+    Expired,
+    Poll,
+    Provide,
+    Read,
+    ReadFixed,
+    ReadProvided,
+    RecvMulti,
+    SendZc,
+    Signal,
+    Sleep,
+    Splice,
+    Timeout,
+    TimeoutRemove,
+    TimeoutUpdate,
+    Write,
+    WriteFixed
+);
+
 impl CompletionType {
     pub fn new(task: task::TaskType, ret: i32) -> Self {
         match task {
-            task::TaskType::Accept(task) => CompletionType::Accept(Accept::new(task, ret)),
+            task::TaskType::Accept(task) => CompletionType::Accept(Accept::new(Some(task), ret)),
+            task::TaskType::AcceptDirect(task) => {
+                CompletionType::AcceptDirect(AcceptDirect::new(task, ret))
+            }
+            task::TaskType::AcceptUnix(task) => {
+                CompletionType::AcceptUnix(AcceptUnix::new(task, ret))
+            }
             task::TaskType::Cancel(task) => CompletionType::Cancel(Cancel::new(task, ret)),
             task::TaskType::Connect(task) => CompletionType::Connect(Connect::new(task, ret)),
+            task::TaskType::Event(task) => CompletionType::Event(Event::new(task, ret)),
+            task::TaskType::Poll(task) => CompletionType::Poll(Poll::new(task, ret)),
+            task::TaskType::Provide(task) => CompletionType::Provide(Provide::new(task, ret)),
             task::TaskType::Read(task) => CompletionType::Read(Read::new(task, ret)),
             task::TaskType::ReadFixed(task) => CompletionType::ReadFixed(ReadFixed::new(task, ret)),
+            task::TaskType::ReadProvided(task) => {
+                CompletionType::ReadProvided(ReadProvided::new(task, ret))
+            }
+            task::TaskType::RecvMulti(task) => {
+                CompletionType::RecvMulti(RecvMulti::new(Some(task), ret))
+            }
+            task::TaskType::SendZc(task) => CompletionType::SendZc(SendZc::new(task, ret)),
+            task::TaskType::Signal(task) => CompletionType::Signal(Signal::new(task, ret)),
+            task::TaskType::Sleep(task) => CompletionType::Sleep(Sleep::new(task, ret)),
+            task::TaskType::Splice(task) => CompletionType::Splice(Splice::new(task, ret)),
             task::TaskType::Timeout(task) => CompletionType::Timeout(Timeout::new(task, ret)),
+            task::TaskType::TimeoutRemove(task) => {
+                CompletionType::TimeoutRemove(TimeoutRemove::new(task, ret))
+            }
+            task::TaskType::TimeoutUpdate(task) => {
+                CompletionType::TimeoutUpdate(TimeoutUpdate::new(task, ret))
+            }
             task::TaskType::Write(task) => CompletionType::Write(Write::new(task, ret)),
             task::TaskType::WriteFixed(task) => CompletionType::WriteFixed(WriteFixed::new(task, ret)),
         }
     }
+
+    // A completion from a repeated task that will complete again, so the task isn't returned yet.
+    pub fn more(task: &mut task::TaskType, ret: i32) -> Self {
+        match task {
+            task::TaskType::Accept(_) => CompletionType::Accept(Accept::new(None, ret)),
+            task::TaskType::RecvMulti(_) => CompletionType::RecvMulti(RecvMulti::new(None, ret)),
+            task::TaskType::SendZc(task) => CompletionType::SendZc(SendZc::sent(task, ret)),
+            _ => unreachable!("task doesn't repeat"),
+        }
+    }
 }
@@ -0,0 +1,16 @@
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::{fs, io};
+
+use nix::sys::eventfd::{self, EfdFlags};
+
+// Create an eventfd, used to wake up a ring from another thread.
+pub fn new() -> anyhow::Result<fs::File> {
+    let fd = eventfd::eventfd(0, EfdFlags::EFD_CLOEXEC)?;
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+// Increment the counter, completing any pending Event task.
+pub fn notify(mut event: &fs::File) -> io::Result<()> {
+    event.write_all(&1u64.to_ne_bytes())
+}
@@ -0,0 +1,469 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::{fs, marker, net, ops, time};
+
+use anyhow::Result;
+use slab::Slab;
+
+use super::completion::{self, CompletionType};
+use super::runtime::Runtime;
+use super::task::{self, TaskId, TaskType};
+use super::{buffer, event, handle, tcp};
+
+type LocalFuture<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+// The waker id of the future passed to block_on, which isn't in the slab.
+const MAIN: usize = usize::MAX;
+
+// Runs futures on a single thread, resolving their tasks from the runtime's completions.
+// Wakers can be sent to other threads, and waking from one ends a wait on the ring through an
+// eventfd that's kept armed.
+pub struct Executor<'a> {
+    spawner: Spawner<'a>,
+    futures: Slab<(LocalFuture<'a>, Waker)>,
+    woken: Arc<Woken>,
+    wakeup: Option<TaskId>, // the read of the eventfd, while it's armed
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(kio: Runtime<'a>) -> Result<Self> {
+        let event = event::new()?;
+
+        let shared = Shared {
+            kio: RefCell::new(kio),
+            slots: RefCell::new(HashMap::new()),
+            spawned: RefCell::new(Vec::new()),
+        };
+
+        let wakeup = shared.kio.borrow_mut().event(event.try_clone()?);
+
+        let woken = Woken {
+            queue: Mutex::new(Queue::default()),
+            event,
+        };
+
+        Ok(Self {
+            spawner: Spawner {
+                shared: Rc::new(shared),
+            },
+            futures: Slab::new(),
+            woken: Arc::new(woken),
+            wakeup: Some(wakeup),
+        })
+    }
+
+    // Returns a spawner for starting tasks and spawning futures, which can be moved into futures.
+    pub fn spawner(&self) -> Spawner<'a> {
+        self.spawner.clone()
+    }
+
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + 'a,
+    {
+        self.spawner.spawn(future)
+    }
+
+    // Run the future to completion, along with any spawned futures in the meantime.
+    // Spawned futures that haven't finished are kept and resumed by the next block_on.
+    // NOTE: Like any executor, this blocks forever if the future is pending without a task running
+    // or a waker held somewhere that will wake it.
+    pub fn block_on<F: Future>(&mut self, future: F) -> Result<F::Output> {
+        let mut future = Box::pin(future);
+        let waker = Wakeup::waker(MAIN, &self.woken);
+
+        self.wake(MAIN);
+
+        loop {
+            while let Some(id) = self.next() {
+                if id != MAIN {
+                    self.poll(id);
+                    continue;
+                }
+
+                let mut cx = Context::from_waker(&waker);
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return Ok(output);
+                }
+            }
+
+            self.complete()?;
+        }
+    }
+
+    // Returns the next future to poll, adding any that were spawned first.
+    fn next(&mut self) -> Option<usize> {
+        let spawned: Vec<_> = self.spawner.shared.spawned.borrow_mut().drain(..).collect();
+
+        for future in spawned {
+            let entry = self.futures.vacant_entry();
+            let id = entry.key();
+
+            entry.insert((future, Wakeup::waker(id, &self.woken)));
+            self.wake(id);
+        }
+
+        self.woken.queue.lock().unwrap().ids.pop_front()
+    }
+
+    fn poll(&mut self, id: usize) {
+        // Wakes can arrive after the future finished, so ignore them.
+        let (future, waker) = match self.futures.get_mut(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let mut cx = Context::from_waker(waker);
+        if future.as_mut().poll(&mut cx).is_ready() {
+            drop(self.futures.remove(id));
+        }
+    }
+
+    // Wait for at least one task to complete, waking the future for each that's ready.
+    // Returns early if a future was woken from another thread in the meantime.
+    fn complete(&mut self) -> Result<()> {
+        {
+            let mut queue = self.woken.queue.lock().unwrap();
+            if !queue.ids.is_empty() {
+                return Ok(());
+            }
+
+            queue.waiting = true;
+        }
+
+        let result = self.spawner.kio().wait();
+        self.woken.queue.lock().unwrap().waiting = false;
+
+        let (id, completion) = result?;
+        self.resolve(id, completion);
+
+        loop {
+            let (id, completion) = match self.spawner.kio().try_wait()? {
+                Some(ready) => ready,
+                None => return Ok(()),
+            };
+
+            self.resolve(id, completion);
+        }
+    }
+
+    // Re-arm the eventfd once it's read, and hand anything else to the Op that's waiting for it.
+    fn resolve(&mut self, id: TaskId, completion: CompletionType) {
+        match completion {
+            CompletionType::Event(event) if self.wakeup == Some(id) => {
+                // It's only cancelled when the runtime shuts down, so leave it then.
+                self.wakeup = match event.count {
+                    Ok(_) => Some(self.spawner.kio().event(event.task.fd)),
+                    Err(_) => None,
+                };
+            }
+            completion => self.spawner.shared.resolve(id, completion),
+        }
+    }
+
+    fn wake(&self, id: usize) {
+        self.woken.queue.lock().unwrap().ids.push_back(id);
+    }
+}
+
+// The kernel still references the sockets and buffers of any task that's running, including the
+// eventfd read and the tasks of unfinished futures, so wait for them all before the runtime's freed.
+impl Drop for Executor<'_> {
+    fn drop(&mut self) {
+        // Dropping their Ops only marks the tasks cancelled, which shutdown then submits.
+        self.futures.clear();
+        self.spawner.shared.spawned.borrow_mut().clear();
+        self.wakeup = None;
+
+        // Nothing's left to return an error to, and the ring is unusable if it fails.
+        let _ = self.spawner.kio().shutdown();
+    }
+}
+
+// The futures to poll, shared with their wakers.
+struct Woken {
+    queue: Mutex<Queue>,
+    event: fs::File, // notified when a future is woken while the executor waits on the ring
+}
+
+#[derive(Default)]
+struct Queue {
+    ids: VecDeque<usize>, // by slab key or MAIN
+    waiting: bool,        // the executor is waiting on the ring
+}
+
+// Queues a future to be polled again.
+struct Wakeup {
+    id: usize,
+    woken: Arc<Woken>,
+}
+
+impl Wakeup {
+    fn waker(id: usize, woken: &Arc<Woken>) -> Waker {
+        let wakeup = Self {
+            id,
+            woken: woken.clone(),
+        };
+
+        Arc::new(wakeup).into()
+    }
+}
+
+impl Wake for Wakeup {
+    fn wake(self: Arc<Self>) {
+        let mut queue = self.woken.queue.lock().unwrap();
+        queue.ids.push_back(self.id);
+
+        // Only needed once per wait, and a failure just leaves it to the next completion.
+        if queue.waiting {
+            queue.waiting = false;
+            let _ = event::notify(&self.woken.event);
+        }
+    }
+}
+
+struct Shared<'a> {
+    kio: RefCell<Runtime<'a>>,
+    slots: RefCell<HashMap<TaskId, Rc<RefCell<Slot>>>>, // running tasks started by an Op
+    spawned: RefCell<Vec<LocalFuture<'a>>>,             // futures waiting to be added
+}
+
+impl Shared<'_> {
+    // Hand the completion to the Op that started the task, if it's still around.
+    // Completions for other tasks, such as cancels, are dropped.
+    fn resolve(&self, id: TaskId, completion: CompletionType) {
+        let slot = match self.slots.borrow_mut().remove(&id) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let mut slot = slot.borrow_mut();
+        slot.completion = Some(completion);
+
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Where a task's completion waits for its Op to be polled.
+#[derive(Default)]
+struct Slot {
+    completion: Option<CompletionType>,
+    waker: Option<Waker>,
+}
+
+// Starts tasks and spawns futures on the executor.
+#[derive(Clone)]
+pub struct Spawner<'a> {
+    shared: Rc<Shared<'a>>,
+}
+
+impl<'a> Spawner<'a> {
+    // The runtime, for anything without a future, such as registering files or buffers.
+    // NOTE: Completions for tasks run directly on it are dropped by the executor.
+    pub fn kio(&self) -> RefMut<'_, Runtime<'a>> {
+        self.shared.kio.borrow_mut()
+    }
+
+    // Run the future on the executor, polling it alongside the others until it finishes.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + 'a,
+    {
+        self.shared.spawned.borrow_mut().push(Box::pin(future));
+    }
+
+    // Resolves once the task completes, as the completion of the same type.
+    // Panics if the accept repeats, since only its first connection would be resolved and the rest
+    // would be closed.
+    pub fn run<K: Resolve>(&self, task: K) -> Op<'a, K::Completion> {
+        let task = task.into();
+
+        if let TaskType::Accept(accept) = &task {
+            assert!(!accept.repeat, "an Op can't run a repeating accept");
+        }
+
+        Op {
+            spawner: self.clone(),
+            task: Some(task),
+            timeout: None,
+            slot: None,
+            running: None,
+            completion: marker::PhantomData,
+        }
+    }
+
+    pub fn accept(&self, socket: net::TcpListener) -> Op<'a, completion::Accept> {
+        let task = task::Accept {
+            socket,
+            repeat: false,
+            multishot: false,
+        };
+
+        self.run(task)
+    }
+
+    pub fn connect(
+        &self,
+        socket: tcp::Reader,
+        addr: net::SocketAddr,
+    ) -> Op<'a, completion::Connect> {
+        self.run(task::Connect::new(socket, addr))
+    }
+
+    pub fn read(&self, socket: tcp::Reader, buffer: buffer::Slice) -> Op<'a, completion::Read> {
+        self.run(task::Read { socket, buffer })
+    }
+
+    pub fn read_fixed(
+        &self,
+        socket: tcp::Reader,
+        buffer: buffer::Fixed,
+    ) -> Op<'a, completion::ReadFixed> {
+        self.run(task::ReadFixed { socket, buffer })
+    }
+
+    // Resolves after the duration has elapsed.
+    pub fn sleep(&self, duration: time::Duration) -> Op<'a, completion::Sleep> {
+        self.run(task::Sleep::new(duration))
+    }
+
+    pub fn write<R>(
+        &self,
+        socket: tcp::Writer,
+        buffer: buffer::Slice,
+        range: R,
+    ) -> Op<'a, completion::Write>
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        self.run(task::Write::new(socket, buffer, range))
+    }
+
+    pub fn write_fixed<R>(
+        &self,
+        socket: tcp::Writer,
+        buffer: buffer::Fixed,
+        range: R,
+    ) -> Op<'a, completion::WriteFixed>
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        self.run(task::WriteFixed::new(socket, buffer, range))
+    }
+}
+
+// A task that can be run by an Op, resolving to the completion of the same name.
+// Tasks that complete more than once, such as a multishot receive or a zero-copy send with its
+// notification, aren't included, since an Op only resolves with the first completion.
+pub trait Resolve: Into<TaskType> {
+    type Completion: TryFrom<CompletionType>;
+}
+
+macro_rules! resolve {
+    ($($name:ident),*) => {
+        $(
+            impl Resolve for task::$name {
+                type Completion = completion::$name;
+            }
+        )*
+    };
+}
+
+resolve!(
+    Accept,
+    AcceptDirect,
+    AcceptUnix,
+    Cancel,
+    Connect,
+    Event,
+    Poll,
+    Provide,
+    Read,
+    ReadFixed,
+    ReadProvided,
+    Signal,
+    Sleep,
+    Splice,
+    Timeout,
+    TimeoutRemove,
+    TimeoutUpdate,
+    Write,
+    WriteFixed
+);
+
+// A task that's run when first polled, resolving to its completion with the socket and buffer.
+// Dropping it early cancels the task, which keeps its socket and buffer until the kernel is done
+// with them, and then drops them.
+pub struct Op<'a, T> {
+    spawner: Spawner<'a>,
+    task: Option<TaskType>, // taken once it's run
+    timeout: Option<time::Duration>,
+    slot: Option<Rc<RefCell<Slot>>>, // set while it's running
+    running: Option<handle::Handle>, // cancels the task if dropped early
+    completion: marker::PhantomData<T>,
+}
+
+impl<T> Op<'_, T> {
+    // Cancel the task if it hasn't finished within the duration, such as a read failing with
+    // ECANCELED, so the buffer is returned either way.
+    pub fn timeout(mut self, duration: time::Duration) -> Self {
+        self.timeout = Some(duration);
+        self
+    }
+}
+
+// Nothing is pinned, since the task and its buffer are moved into the runtime.
+impl<T> Unpin for Op<'_, T> {}
+
+impl<T> Future for Op<'_, T>
+where
+    T: TryFrom<CompletionType>,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let this = &mut *self;
+
+        if let Some(task) = this.task.take() {
+            let running = this.spawner.kio().run_timeout(task, this.timeout);
+            let slot = Rc::new(RefCell::new(Slot::default()));
+
+            this.spawner
+                .shared
+                .slots
+                .borrow_mut()
+                .insert(running.id(), slot.clone());
+            this.slot = Some(slot);
+            this.running = Some(running);
+        }
+
+        let slot = this.slot.as_ref().expect("polled after completion");
+        let mut slot = slot.borrow_mut();
+
+        match slot.completion.take() {
+            Some(completion) => {
+                drop(slot);
+                this.slot = None;
+                this.running = None;
+
+                // Resolve ties T to the task, and the runtime completes each task as its own type.
+                match T::try_from(completion) {
+                    Ok(completion) => Poll::Ready(completion),
+                    Err(_) => unreachable!("the runtime completed a task as another type"),
+                }
+            }
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// A slot in the ring's table of registered files, given back to the table once dropped.
+pub struct Slot {
+    index: u32,
+    released: Rc<RefCell<Vec<u32>>>,
+}
+
+impl Slot {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+impl std::ops::Drop for Slot {
+    fn drop(&mut self) {
+        self.released.borrow_mut().push(self.index);
+    }
+}
+
+// Tracks which slots in the sparse file table are in use.
+// NOTE: The kernel holds a reference to each registered file, so released slots must be cleared
+// before the file is really closed.
+#[derive(Default)]
+pub struct Table {
+    free: Vec<u32>,
+    released: Rc<RefCell<Vec<u32>>>, // dropped but not yet cleared
+}
+
+impl Table {
+    pub fn new(count: u32) -> Self {
+        Self {
+            free: (0..count).rev().collect(),
+            released: Rc::default(),
+        }
+    }
+
+    pub fn take(&mut self) -> Option<Slot> {
+        let index = self.free.pop()?;
+
+        Some(Slot {
+            index,
+            released: self.released.clone(),
+        })
+    }
+
+    // Returns the slots that were dropped since the last call, which should be cleared and given back.
+    pub fn released(&mut self) -> Vec<u32> {
+        self.released.take()
+    }
+
+    pub fn give(&mut self, index: u32) {
+        self.free.push(index);
+    }
+}
@@ -0,0 +1,102 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::task::TaskId;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Running,
+    Cancelled, // dropped or cancelled before completing, waiting for the cancel to be submitted
+    Detached,  // left to run on its own
+    Done,
+}
+
+// Owns a running task, cancelling it once dropped unless detached.
+// The runtime keeps the task, along with its socket and buffer, until the kernel completes it,
+// so cancelling is always safe. The completion is still returned by wait, failing with ECANCELED
+// unless the task finished first.
+#[must_use = "dropping the handle cancels the task; use detach to let it run"]
+pub struct Handle {
+    id: TaskId,
+    status: Rc<Cell<Status>>,
+    cancelled: Rc<RefCell<Vec<TaskId>>>,
+}
+
+impl Handle {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    // Returns true until the task's final completion has been taken from the runtime.
+    pub fn is_running(&self) -> bool {
+        self.status.get() == Status::Running
+    }
+
+    // Cancel the task, the same as dropping the handle. The cancel is submitted along with the next
+    // batch, and the completion still comes back from wait so the buffer isn't lost.
+    pub fn cancel(self) {
+        drop(self)
+    }
+
+    // Let the task run to completion, returning its id to match the completion against.
+    pub fn detach(self) -> TaskId {
+        if self.is_running() {
+            self.status.set(Status::Detached);
+        }
+
+        self.id
+    }
+}
+
+impl std::ops::Drop for Handle {
+    fn drop(&mut self) {
+        if self.is_running() {
+            self.status.set(Status::Cancelled);
+            self.cancelled.borrow_mut().push(self.id);
+        }
+    }
+}
+
+// Tracks the tasks that have handles, so they're only cancelled while still running.
+// NOTE: Task ids are reused once a task completes, so a handle dropped afterwards must not cancel
+// whichever task has its id now.
+#[derive(Default)]
+pub struct Table {
+    running: HashMap<TaskId, Rc<Cell<Status>>>,
+    cancelled: Rc<RefCell<Vec<TaskId>>>, // dropped but not yet cancelled
+}
+
+impl Table {
+    pub fn insert(&mut self, id: TaskId) -> Handle {
+        let status = Rc::new(Cell::new(Status::Running));
+        self.running.insert(id, status.clone());
+
+        Handle {
+            id,
+            status,
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    // Called once the task's final completion is taken.
+    pub fn complete(&mut self, id: TaskId) {
+        if let Some(status) = self.running.remove(&id) {
+            status.set(Status::Done);
+        }
+    }
+
+    // Returns the tasks that were cancelled since the last call and are still running.
+    pub fn cancelled(&mut self) -> Vec<TaskId> {
+        let cancelled = self.cancelled.take();
+
+        cancelled
+            .into_iter()
+            .filter(|id| self.status(*id) == Some(Status::Cancelled))
+            .collect()
+    }
+
+    fn status(&self, id: TaskId) -> Option<Status> {
+        self.running.get(&id).map(|status| status.get())
+    }
+}
@@ -1,7 +1,17 @@
 pub mod buffer;
+pub mod chain;
 pub mod completion;
+pub mod event;
+pub mod executor;
+pub mod files;
+pub mod handle;
+pub mod pipe;
+pub mod probe;
 mod runtime;
+pub mod setup;
+pub mod signal;
 pub mod task;
 pub mod tcp;
+pub mod timer;
 
 pub use runtime::Runtime as Kio;
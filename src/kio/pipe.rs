@@ -0,0 +1,22 @@
+use std::fs;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use nix::fcntl::{self, FcntlArg, OFlag};
+use nix::unistd;
+
+// Create a kernel pipe, returning the read and write ends.
+// NOTE: The pipe is left blocking, since splices on a ring run in the background anyway.
+pub fn new() -> anyhow::Result<(fs::File, fs::File)> {
+    let (reader, writer) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+
+    let reader = unsafe { fs::File::from_raw_fd(reader) };
+    let writer = unsafe { fs::File::from_raw_fd(writer) };
+
+    Ok((reader, writer))
+}
+
+// The number of bytes the pipe can hold before splicing into it blocks.
+pub fn capacity(pipe: &fs::File) -> anyhow::Result<usize> {
+    let size = fcntl::fcntl(pipe.as_raw_fd(), FcntlArg::F_GETPIPE_SZ)?;
+    Ok(size as usize)
+}
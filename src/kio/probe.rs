@@ -0,0 +1,99 @@
+use std::fmt;
+
+use io_uring::{opcode, IoUring};
+
+use super::task;
+
+// Opcodes that io_uring doesn't expose yet, used to tell which kernel we're on.
+// IORING_OP_SHUTDOWN arrived in 5.11, when SQPOLL stopped needing privileges, waits could time out
+// on their own and timeouts could be moved.
+const OP_SHUTDOWN: u8 = 34;
+
+// IORING_OP_MKDIRAT arrived in 5.15, along with accepting straight into the file table.
+const OP_MKDIRAT: u8 = 37;
+
+// IORING_OP_SOCKET arrived in 5.19, along with multishot accepts and buffer rings.
+const OP_SOCKET: u8 = 45;
+
+// What the kernel supports, probed once when the runtime starts.
+// Features without their own opcode are inferred from one that arrived in the same release.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub fast_poll: bool,        // sockets are polled instead of read by a thread
+    pub direct_accept: bool,    // accepts can fill the file table
+    pub multishot_accept: bool, // accepts repeat
+    pub multishot_recv: bool,   // receives repeat, picking a buffer for each
+    pub buffer_ring: bool,      // provided buffers are shared through a ring
+    pub sqpoll: bool,           // submissions can be polled without privileges
+    pub wait_timeout: bool,     // waits can time out without a timeout task
+    pub timeout_update: bool,   // sleeps can be given a new deadline
+    pub send_zc: bool,          // sends can skip copying the data
+    pub splice: bool,           // data can move through a kernel pipe
+    opcodes: Vec<bool>,         // indexed by opcode, all false if unprobed
+}
+
+impl Capabilities {
+    pub fn new(uring: &IoUring) -> Self {
+        let mut probe = io_uring::Probe::new();
+
+        // Probing was added in 5.6, along with most of the newer opcodes.
+        let opcodes = match uring.submitter().register_probe(&mut probe) {
+            Ok(()) => (0..=u8::MAX).map(|op| probe.is_supported(op)).collect(),
+            Err(_) => vec![false; 1 << 8],
+        };
+
+        Self {
+            fast_poll: uring.params().is_feature_fast_poll(),
+            direct_accept: opcodes[OP_MKDIRAT as usize],
+            multishot_accept: opcodes[OP_SOCKET as usize],
+            // Zero-copy sends arrived in 6.0 along with multishot receives.
+            multishot_recv: opcodes[task::SendZc::CODE as usize],
+            buffer_ring: opcodes[OP_SOCKET as usize],
+            sqpoll: opcodes[OP_SHUTDOWN as usize],
+            wait_timeout: opcodes[OP_SHUTDOWN as usize],
+            timeout_update: opcodes[OP_SHUTDOWN as usize],
+            send_zc: opcodes[task::SendZc::CODE as usize],
+            splice: opcodes[opcode::Splice::CODE as usize],
+            opcodes,
+        }
+    }
+
+    // Returns true if the kernel supports the opcode, such as opcode::Splice::CODE.
+    pub fn supports(&self, opcode: u8) -> bool {
+        self.opcodes[opcode as usize]
+    }
+}
+
+// Lists the features that are available, such as "fast-poll send-zc splice".
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let features = [
+            ("fast-poll", self.fast_poll),
+            ("direct-accept", self.direct_accept),
+            ("multishot-accept", self.multishot_accept),
+            ("multishot-recv", self.multishot_recv),
+            ("buffer-ring", self.buffer_ring),
+            ("sqpoll", self.sqpoll),
+            ("wait-timeout", self.wait_timeout),
+            ("timeout-update", self.timeout_update),
+            ("send-zc", self.send_zc),
+            ("splice", self.splice),
+        ];
+
+        let mut first = true;
+        for (name, _) in features.iter().filter(|(_, available)| *available) {
+            if !first {
+                write!(f, " ")?;
+            }
+
+            write!(f, "{}", name)?;
+            first = false;
+        }
+
+        if first {
+            write!(f, "none")?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,47 +1,181 @@
-use std::collections::LinkedList;
-use std::{net, ops, time};
-
-use super::completion::CompletionType;
+use std::collections::{HashMap, LinkedList};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::{cmp, fs, io, mem, net, ops, time};
+
+use super::chain::{self, Chain, Links};
+use super::completion::{self, CompletionType};
+use super::handle::{self, Handle};
+use super::probe::Capabilities;
 use super::task::{Task, TaskId, TaskType};
-use super::{buffer, task, tcp};
+use super::timer::{self, TimerId};
+use super::{buffer, files, task, tcp};
 
 use io_uring::squeue::{Entry, Flags};
 use io_uring::IoUring;
+use io_uring::{opcode, types};
 
 use anyhow::Result;
+use nix::sys::signalfd::SignalFd;
 use slab::Slab;
 
+// Set in the completion flags when the kernel picked a provided buffer, with its id in the top bits.
+const CQE_F_BUFFER: u32 = 1;
+const CQE_BUFFER_SHIFT: u32 = 16;
+
+// Set in the completion flags when a multishot task will complete again.
+const CQE_F_MORE: u32 = 1 << 1;
+
+// Flags for io_uring_enter, for waiting with a timeout, which io_uring doesn't support yet.
+const ENTER_GETEVENTS: libc::c_uint = 1;
+const ENTER_EXT_ARG: libc::c_uint = 1 << 3;
+
+// The kernel's io_uring_getevents_arg, passed to io_uring_enter with ENTER_EXT_ARG.
+#[repr(C)]
+struct GeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64, // pointer to the timeout
+}
+
+// The user data of the timeout task that ends a wait on kernels without ENTER_EXT_ARG.
+// It's never a task id, so it's skipped when reaping.
+const WAKE: u64 = u64::MAX;
+
+// The user data of the timeout that fires for the next slot of the timer wheel, and of the tasks
+// that move it earlier. Expired timers are returned with TICK instead of a task id.
+const TICK: u64 = u64::MAX - 1;
+const TICK_UPDATE: u64 = u64::MAX - 2;
+
 pub struct Runtime<'a> {
+    fd: RawFd, // the ring, for registering what io_uring doesn't support yet
     submitter: io_uring::Submitter<'a>,
-    submissions: io_uring::squeue::AvailableQueue<'a>,
-    completions: io_uring::cqueue::AvailableQueue<'a>,
+    submissions: io_uring::SubmissionQueue<'a>,
+    completions: io_uring::CompletionQueue<'a>,
 
     tasks: Slab<TaskType>,
+    handles: handle::Table, // tasks that are cancelled once their handle is dropped
+    chains: chain::Table,   // tasks linked by a chain, recording how each finished
     backlog: LinkedList<Entry>,
+    backlog_peak: usize,        // the deepest the backlog has been
+    linking: Vec<Entry>,        // linked entries held back until their chain ends
+    error: Option<io::Error>,   // a failed submit while pushing, returned by the next wait
+    wake: Box<types::Timespec>, // ends a wait on older kernels, boxed for a stable address
+
+    timers: timer::Wheel,
+    ticks: Vec<time::Instant>, // deadlines of the kernel timeouts in flight for the wheel
+    tick: Box<types::Timespec>, // the latest of those deadlines, boxed for a stable address
 
     buffers: buffer::Pool,
+    provided: HashMap<usize, buffer::Fixed>, // buffers lent to the kernel, by id
+    ring: Option<buffer::Ring>,              // set if buffers are provided through a ring
+
+    files: files::Table,
+
+    capabilities: Capabilities,
+    sqpoll: bool,  // a kernel thread submits tasks, so they only need to be published
+    closing: bool, // set by shutdown so repeated tasks stop resubmitting
 }
 
 impl<'a> Runtime<'a> {
+    // Missing features are worked around, so check capabilities to pick what to use.
     pub fn new(uring: &'a mut IoUring) -> Result<Self> {
-        if !uring.params().is_feature_fast_poll() {
-            anyhow::bail!("missing fast poll");
-        }
+        let capabilities = Capabilities::new(uring);
+        let sqpoll = uring.params().is_setup_sqpoll();
 
+        let fd = uring.as_raw_fd();
         let (submitter, submissions, completions) = uring.split();
 
         Ok(Self {
+            fd,
             submitter,
-            submissions: submissions.available(),
-            completions: completions.available(),
+            submissions,
+            completions,
 
             tasks: Slab::new(),
+            handles: handle::Table::default(),
+            chains: chain::Table::default(),
             backlog: LinkedList::new(),
+            backlog_peak: 0,
+            linking: Vec::new(),
+            error: None,
+            wake: Box::new(types::Timespec::default()),
+
+            timers: timer::Wheel::default(),
+            ticks: Vec::new(),
+            tick: Box::new(types::Timespec::default()),
 
             buffers: buffer::Pool::default(),
+            provided: HashMap::new(),
+            ring: None,
+
+            files: files::Table::default(),
+
+            capabilities,
+            sqpoll,
+            closing: false,
         })
     }
 
+    // Register an empty file table with room for count sockets, capped to the open file limit.
+    // Sockets are added to it by register as they're accepted or created.
+    pub fn register_files(&mut self, count: u32) -> Result<u32> {
+        let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let count = cmp::min(count as u64, limit.rlim_cur) as u32;
+
+        // -1 leaves the slot empty.
+        self.submitter.register_files(&vec![-1; count as usize])?;
+        self.files = files::Table::new(count);
+
+        Ok(count)
+    }
+
+    // Add the socket to the file table so tasks can skip the descriptor lookup, and split it.
+    // The descriptor is used instead if the table is full or wasn't registered.
+    pub fn register(&mut self, stream: net::TcpStream) -> (tcp::Reader, tcp::Writer) {
+        self.clear_files();
+
+        let slot = self.files.take().filter(|slot| {
+            let fds = [stream.as_raw_fd()];
+            self.submitter
+                .register_files_update(slot.index(), &fds)
+                .is_ok()
+        });
+
+        tcp::split_registered(stream, slot)
+    }
+
+    // Empty the slots of sockets that were dropped, so the kernel releases them, and reuse them.
+    fn clear_files(&mut self) {
+        for index in self.files.released() {
+            if self.submitter.register_files_update(index, &[-1]).is_ok() {
+                self.files.give(index);
+            }
+        }
+    }
+
+    // Submit cancels for the tasks whose handles were dropped while they were still running.
+    fn cancel_dropped(&mut self) {
+        for id in self.handles.cancelled() {
+            self.cancel(id);
+        }
+    }
+
+    // What the kernel supports, probed when the runtime started.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    // Returns true if the kernel supports the opcode, such as opcode::Splice::CODE.
+    pub fn supports(&self, opcode: u8) -> bool {
+        self.capabilities.supports(opcode)
+    }
+
     // NOTE: will wait for the ring to idle.
     pub fn prepare_buffers(&mut self, count: usize, size: usize) -> Result<()> {
         let mut register_buffers = Vec::with_capacity(count);
@@ -67,12 +201,98 @@ impl<'a> Runtime<'a> {
         &mut self.buffers
     }
 
+    // Move buffers from the pool to the kernel, for reads with provided buffers.
+    // They're shared through the ring if one was registered, or provided with tasks otherwise.
+    pub fn provide_buffers(&mut self, count: usize) {
+        for _ in 0..count {
+            match self.buffers.take() {
+                Some(buffer) => self.provide(buffer),
+                None => return,
+            };
+        }
+    }
+
+    // The number of buffers the kernel has to pick from.
+    pub fn provided(&self) -> usize {
+        self.provided.len()
+    }
+
+    // Give the buffer back to the kernel once a provided read is done with it.
+    pub fn provide(&mut self, mut buffer: buffer::Fixed) {
+        if let Some(ring) = &mut self.ring {
+            ring.push(&mut buffer);
+            self.provided.insert(buffer.id(), buffer);
+            return;
+        }
+
+        let task = task::Provide::new(buffer::PROVIDED_GROUP, &mut buffer);
+        self.provided.insert(buffer.id(), buffer);
+
+        self.start(task.into());
+    }
+
+    // Register a ring with room for count buffers as the provided group, so giving one back doesn't
+    // need a task. Must be called before any buffers are provided.
+    // Kernels before 5.19 don't support it, and it can't hold more than 32768 buffers.
+    pub fn register_ring(&mut self, count: usize) -> io::Result<()> {
+        let size = match count.checked_next_power_of_two() {
+            Some(size) if size <= 1 << 15 => size as u16,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "too many buffers for a ring",
+                ))
+            }
+        };
+
+        let ring = buffer::Ring::register(self.fd, size, buffer::PROVIDED_GROUP)?;
+        self.ring = Some(ring);
+
+        Ok(())
+    }
+
     pub fn accept(&mut self, socket: net::TcpListener) -> TaskId {
-        self.run(task::Accept { socket }.into())
+        let task = task::Accept {
+            socket,
+            repeat: false,
+            multishot: false,
+        };
+
+        self.start(task.into())
+    }
+
+    // Keep accepting connections, completing once for each until cancelled or failed.
+    // The final completion returns the task, so the listener can be used again.
+    // Kernels without multishot accepts are emulated by resubmitting after each connection.
+    pub fn accept_multi(&mut self, socket: net::TcpListener) -> TaskId {
+        let task = task::Accept {
+            socket,
+            repeat: true,
+            multishot: self.capabilities.multishot_accept,
+        };
+
+        self.start(task.into())
+    }
+
+    // Accept a connection straight into a free slot in the file table, returning its peer address.
+    // Falls back to a descriptor if the table is full or the kernel can't accept directly.
+    pub fn accept_direct(&mut self, socket: net::TcpListener) -> TaskId {
+        self.clear_files();
+
+        let slot = match self.capabilities.direct_accept {
+            true => self.files.take(),
+            false => None,
+        };
+
+        self.start(task::AcceptDirect::new(socket, slot).into())
+    }
+
+    pub fn accept_unix(&mut self, socket: UnixListener) -> TaskId {
+        self.start(task::AcceptUnix { socket }.into())
     }
 
     pub fn cancel(&mut self, id: TaskId) -> TaskId {
-        self.run(task::Cancel { id }.into())
+        self.start(task::Cancel { id }.into())
     }
 
     pub fn cancel_then(&mut self, id: TaskId) -> TaskId {
@@ -80,15 +300,20 @@ impl<'a> Runtime<'a> {
     }
 
     pub fn connect(&mut self, socket: tcp::Reader, addr: net::SocketAddr) -> TaskId {
-        self.run(task::Connect::new(socket, addr).into())
+        self.start(task::Connect::new(socket, addr).into())
     }
 
     pub fn connect_then(&mut self, socket: tcp::Reader, addr: net::SocketAddr) -> TaskId {
         self.run_then(task::Connect::new(socket, addr).into())
     }
 
+    // Completes when the eventfd is notified.
+    pub fn event(&mut self, fd: fs::File) -> TaskId {
+        self.start(task::Event::new(fd).into())
+    }
+
     pub fn read(&mut self, socket: tcp::Reader, buffer: buffer::Slice) -> TaskId {
-        self.run(task::Read { socket, buffer }.into())
+        self.start(task::Read { socket, buffer }.into())
     }
 
     pub fn read_then(&mut self, socket: tcp::Reader, buffer: buffer::Slice) -> TaskId {
@@ -96,15 +321,96 @@ impl<'a> Runtime<'a> {
     }
 
     pub fn read_fixed(&mut self, socket: tcp::Reader, buffer: buffer::Fixed) -> TaskId {
-        self.run(task::ReadFixed { socket, buffer }.into())
+        self.start(task::ReadFixed { socket, buffer }.into())
     }
 
     pub fn read_fixed_then(&mut self, socket: tcp::Reader, buffer: buffer::Fixed) -> TaskId {
         self.run_then(task::ReadFixed { socket, buffer }.into())
     }
 
-    // Applies a timeout to the next chain of tasks.
+    // Read into a provided buffer, reading at most len bytes.
+    pub fn read_provided(&mut self, socket: tcp::Reader, len: usize) -> TaskId {
+        self.start(task::ReadProvided::new(socket, len).into())
+    }
+
+    // Keep receiving into provided buffers, completing once for each chunk, reading at most len
+    // bytes at once. The final completion returns the task, after EOF, an error or ENOBUFS.
+    // Kernels without multishot receives are emulated by resubmitting after each chunk.
+    pub fn recv_multi(&mut self, socket: tcp::Reader, len: usize) -> TaskId {
+        self.start(task::RecvMulti::new(socket, len, self.capabilities.multishot_recv).into())
+    }
+
+    // Send without copying, completing once with the result and the socket, and again once the
+    // kernel is done with the buffer.
+    pub fn send_zc<R>(&mut self, socket: tcp::Writer, buffer: buffer::Buffer, range: R) -> TaskId
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        self.start(task::SendZc::new(socket, buffer, range).into())
+    }
+
+    // Completes when the next signal arrives on the signalfd.
+    pub fn signal(&mut self, fd: SignalFd) -> TaskId {
+        self.start(task::Signal::new(fd).into())
+    }
+
+    // Completes after the duration has elapsed.
+    pub fn sleep(&mut self, duration: time::Duration) -> TaskId {
+        self.start(task::Sleep::new(duration).into())
+    }
+
+    // Completes once the deadline has passed.
+    pub fn sleep_until(&mut self, deadline: time::Instant) -> TaskId {
+        self.start(task::Sleep::until(deadline).into())
+    }
+
+    // Stop a sleep early, which then fails with ECANCELED.
+    pub fn remove_sleep(&mut self, id: TaskId) -> TaskId {
+        self.start(task::TimeoutRemove { id }.into())
+    }
+
+    // Restart a sleep that hasn't elapsed yet with a new duration.
+    // NOTE: Requires Linux 5.11, see Capabilities::timeout_update.
+    pub fn update_sleep(&mut self, id: TaskId, duration: time::Duration) -> TaskId {
+        self.start(task::TimeoutUpdate::new(id, duration).into())
+    }
+
+    // Start a timer that's returned by wait as an Expired completion once the deadline passes.
+    // Timers share a single kernel timeout, so unlike sleeps, thousands cost no more than one, but
+    // they're only as precise as a millisecond.
+    pub fn timer(&mut self, deadline: time::Instant) -> TimerId {
+        let id = self.timers.insert(deadline);
+        self.arm_timers();
+        id
+    }
+
+    // Move the timer to a new deadline, such as an idle deadline after some activity.
+    // Returns false if it already expired, in which case it's still returned by wait.
+    pub fn reset_timer(&mut self, id: TimerId, deadline: time::Instant) -> bool {
+        let pending = self.timers.reset(id, deadline);
+        self.arm_timers();
+        pending
+    }
+
+    // Stop the timer. Returns false if it already expired, in which case it's still returned by
+    // wait.
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        self.timers.remove(id)
+    }
+
+    // The number of timers, including those expired but not yet returned by wait.
+    pub fn timers(&self) -> usize {
+        self.timers.len()
+    }
+
+    // Applies a timeout to the previous task, which must have been run with a _then variant.
+    // The task is cancelled if it hasn't finished before the duration elapses.
     pub fn timeout(&mut self, duration: time::Duration) -> TaskId {
+        self.start(task::Timeout::new(duration).into())
+    }
+
+    // Like timeout, but also blocks the next task until the previous one has finished successfully.
+    pub fn timeout_then(&mut self, duration: time::Duration) -> TaskId {
         self.run_then(task::Timeout::new(duration).into())
     }
 
@@ -112,7 +418,7 @@ impl<'a> Runtime<'a> {
     where
         R: ops::RangeBounds<usize>,
     {
-        self.run(task::Write::new(socket, buffer, range).into())
+        self.start(task::Write::new(socket, buffer, range).into())
     }
 
     pub fn write_then<R>(&mut self, socket: tcp::Writer, buffer: buffer::Slice, range: R) -> TaskId
@@ -126,7 +432,7 @@ impl<'a> Runtime<'a> {
     where
         R: ops::RangeBounds<usize>,
     {
-        self.run(task::WriteFixed::new(socket, buffer, range).into())
+        self.start(task::WriteFixed::new(socket, buffer, range).into())
     }
 
     pub fn write_fixed_then<R>(
@@ -141,9 +447,10 @@ impl<'a> Runtime<'a> {
         self.run_then(task::WriteFixed::new(socket, buffer, range).into())
     }
 
-    /// Run the given task asynchronously.
-    pub fn run(&mut self, task: TaskType) -> TaskId {
-        self.run_flags(task, Flags::empty())
+    /// Run the given task asynchronously, cancelling it if the handle is dropped.
+    pub fn run(&mut self, task: TaskType) -> Handle {
+        let id = self.start(task);
+        self.handles.insert(id)
     }
 
     /// Run the task and block the next task until this one has finished successfully.
@@ -162,74 +469,502 @@ impl<'a> Runtime<'a> {
         self.run_flags(task, Flags::IO_DRAIN)
     }
 
+    /// Run the task, cancelling it if it hasn't finished within the optional duration or if the
+    /// handle is dropped.
+    pub fn run_timeout(&mut self, task: TaskType, timeout: Option<time::Duration>) -> Handle {
+        let id = match timeout {
+            Some(duration) => {
+                let id = self.run_then(task);
+                self.timeout(duration);
+                id
+            }
+            None => self.start(task),
+        };
+
+        self.handles.insert(id)
+    }
+
+    // Build a chain of linked tasks, such as kio.chain().connect(..).timeout(..).write(..).submit().
+    pub fn chain(&mut self) -> Chain<'_, 'a> {
+        Chain::new(self)
+    }
+
+    // Link the tasks, each starting once the previous one succeeded, and push them together.
+    pub(super) fn run_chain(&mut self, tasks: Vec<TaskType>, links: Vec<chain::Link>) -> Links {
+        assert!(
+            tasks.len() <= self.submissions.capacity(),
+            "chain is longer than the submission queue"
+        );
+
+        let last = tasks.len().saturating_sub(1);
+        let mut ids = Vec::with_capacity(tasks.len());
+
+        for (index, mut task) in tasks.into_iter().enumerate() {
+            let flags = match links[index] {
+                _ if index == last => Flags::empty(),
+                chain::Link::Sleep | chain::Link::Partial => Flags::IO_HARDLINK,
+                _ => Flags::IO_LINK,
+            };
+
+            let entry = task.entry();
+            let id = self.tasks.insert(task);
+
+            // The linked entries are held back until the last one, and then pushed together.
+            self.push(entry.user_data(id as _).flags(flags));
+            ids.push(id);
+        }
+
+        self.chains.insert(ids, links)
+    }
+
+    // Run the task without a handle, for the methods above that return its id.
+    fn start(&mut self, task: TaskType) -> TaskId {
+        self.run_flags(task, Flags::empty())
+    }
+
     fn run_flags(&mut self, mut task: TaskType, flags: Flags) -> TaskId {
         let entry = task.entry();
         let id = self.tasks.insert(task);
         let entry = entry.user_data(id as _).flags(flags);
 
-        if self.submissions.is_full() {
-            self.backlog.push_back(entry);
+        self.push(entry);
+
+        id
+    }
+
+    // Queue the entry, holding linked entries back until their chain ends so it's pushed whole.
+    fn push(&mut self, entry: Entry) {
+        let linked = task::is_linked(&entry);
+        self.linking.push(entry);
+
+        if !linked {
+            self.push_linked();
+        }
+    }
+
+    // Push the held back entries to the submission queue, submitting to the kernel first if there
+    // isn't room. They go behind the backlog if it isn't empty or the kernel can't take more yet,
+    // so tasks are always submitted in order.
+    fn push_linked(&mut self) {
+        let len = self.linking.len();
+
+        if !self.backlog.is_empty() || self.room() < len {
+            self.flush();
+        }
+
+        if self.backlog.is_empty() && self.room() >= len {
+            for entry in self.linking.drain(..) {
+                unsafe {
+                    if self.submissions.push(&entry).is_err() {
+                        // Only if the room was miscounted, but keep the entry regardless.
+                        self.backlog.push_back(entry);
+                    }
+                }
+            }
         } else {
-            unsafe {
-                self.submissions.push(entry).ok();
+            self.backlog.extend(self.linking.drain(..));
+        }
+
+        self.backlog_peak = cmp::max(self.backlog_peak, self.backlog.len());
+    }
+
+    // Submit the queue to the kernel to make room, and move over what fits from the backlog.
+    fn flush(&mut self) {
+        self.submissions.sync();
+
+        if let Err(err) = self.submitter.submit() {
+            match err.raw_os_error() {
+                // The kernel can't take more until completions are reaped, so keep the backlog.
+                Some(libc::EBUSY) | Some(libc::EAGAIN) | Some(libc::EINTR) => {}
+                _ => {
+                    self.error.get_or_insert(err);
+                }
             }
         }
 
-        id
+        self.submissions.sync();
+        self.move_backlog();
+    }
+
+    // The number of entries that can be pushed before the submission queue is full.
+    fn room(&self) -> usize {
+        self.submissions.capacity() - self.submissions.len()
+    }
+
+    // The number of entries waiting for room in the submission queue.
+    pub fn backlog(&self) -> usize {
+        self.backlog.len()
+    }
+
+    // The deepest the backlog has been, which is a sign the ring is too small if it's often deep.
+    pub fn backlog_peak(&self) -> usize {
+        self.backlog_peak
     }
 
+    // Returns true if a repeated task will complete again, resubmitting it if the kernel won't.
+    fn repeats(&mut self, id: TaskId, flags: u32, ret: i32) -> bool {
+        let task = match self.tasks.get_mut(id) {
+            Some(task) => task,
+            None => return false,
+        };
+
+        // Receives also stop at EOF. Zero-copy sends are never resubmitted, but a notification
+        // follows the result if it's flagged.
+        let (multishot, again) = match task {
+            TaskType::Accept(accept) if accept.repeat => (accept.multishot, ret >= 0),
+            TaskType::RecvMulti(recv) => (recv.multishot, ret > 0),
+            TaskType::SendZc(_) => (true, false),
+            _ => return false,
+        };
+
+        if multishot {
+            return flags & CQE_F_MORE != 0;
+        }
+
+        if !again || self.closing {
+            return false;
+        }
+
+        // Emulate a multishot task by submitting it again with the same id.
+        let entry = task.entry().user_data(id as _);
+        self.push(entry);
+
+        true
+    }
+
+    // Wait for the next completion, submitting new tasks first if none are ready.
     pub fn wait(&mut self) -> Result<(TaskId, CompletionType)> {
-        let entry = match self.completions.next() {
-            Some(e) => e,
+        if let Some(completion) = self.try_wait()? {
+            return Ok(completion);
+        }
+
+        loop {
+            // Perform the syscall and wait for 1 task to be done.
+            self.enter(1, None)?;
+
+            if let Some(completion) = self.reap() {
+                return Ok(completion);
+            }
+        }
+    }
+
+    // Submit new tasks and wait until at least min completions are ready or the timeout elapses,
+    // with a single syscall. Take the ready completions with try_wait or ready afterwards, which
+    // may be fewer than min after a timeout, and more otherwise.
+    // NOTE: Kernels before 5.11 can't wait with a timeout, so they only wait for one completion.
+    pub fn wait_batch(&mut self, min: usize, timeout: Option<time::Duration>) -> Result<()> {
+        self.completions.sync();
+
+        if self.completions.len() < min {
+            return self.enter(min, timeout);
+        }
+
+        // Enough are ready already, but start on any new tasks in the meantime.
+        self.publish()
+    }
+
+    // Returns the next completion that's ready, without waiting for one.
+    pub fn try_wait(&mut self) -> Result<Option<(TaskId, CompletionType)>> {
+        if self.sqpoll {
+            self.publish()?;
+        }
+
+        if let Some(completion) = self.reap() {
+            return Ok(Some(completion));
+        }
+
+        // Try to refresh once instead of the costlier syscall.
+        self.completions.sync();
+
+        Ok(self.reap())
+    }
+
+    // Takes every completion that's ready, such as after wait_batch, without waiting for more.
+    pub fn ready(&mut self) -> Ready<'_, 'a> {
+        self.completions.sync();
+        Ready { runtime: self }
+    }
+
+    // Submit new tasks and wait for at least want completions, or until the timeout elapses.
+    fn enter(&mut self, want: usize, timeout: Option<time::Duration>) -> Result<()> {
+        // Refresh our view of the queue, since the kernel may have consumed entries.
+        self.submissions.sync();
+
+        // Let the kernel close any sockets that were dropped.
+        self.clear_files();
+
+        // Cancel the tasks whose handles were dropped.
+        self.cancel_dropped();
+
+        // Push any backlog items before submit/wait
+        self.run_backlog();
+
+        if let Some(err) = self.error.take() {
+            return Err(err.into());
+        }
+
+        if let (Some(timeout), false) = (timeout, self.capabilities.wait_timeout) {
+            // Wake on the timeout or the first completion, whichever comes first.
+            *self.wake = task::timespec(timeout);
+
+            let entry = opcode::Timeout::new(&*self.wake).count(1).build();
+            self.push(entry.user_data(WAKE));
+        }
+
+        // Make sure we flush our new tasks first.
+        self.submissions.sync();
+
+        match timeout {
+            Some(timeout) if self.capabilities.wait_timeout => self.enter_timeout(want, timeout)?,
+            // The timeout task only ends the wait if nothing else does.
+            Some(_) => {
+                self.submitter.submit_and_wait(1)?;
+            }
+            // With SQPOLL this only waits, waking the polling thread if it slept.
             None => {
-                // Try to refresh once instead of the costlier syscall.
-                self.completions.sync();
+                self.submitter.submit_and_wait(want)?;
+            }
+        }
 
-                match self.completions.next() {
-                    Some(e) => e,
-                    None => {
-                        // Push any backlog items before submit/wait
-                        self.run_backlog();
+        // Fetch the new completions.
+        self.completions.sync();
 
-                        // Make sure we flush our new tasks first.
-                        self.submissions.sync();
+        Ok(())
+    }
 
-                        // Perform the syscall and wait for 1 task to be done.
-                        self.submitter.submit_and_wait(1)?;
+    // Like submit_and_wait, but giving up once the timeout elapses.
+    fn enter_timeout(&mut self, want: usize, timeout: time::Duration) -> io::Result<()> {
+        // The polling thread submits, but may need waking first.
+        let submit = match self.sqpoll {
+            true => {
+                self.submitter.submit()?;
+                0
+            }
+            false => self.submissions.len(),
+        };
 
-                        // Fetch the new completion.
-                        self.completions.sync();
+        let timeout = task::timespec(timeout);
 
-                        // Get the guaranteed completion.
-                        self.completions.next().unwrap()
-                    }
-                }
+        let arg = GeteventsArg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            pad: 0,
+            ts: &timeout as *const types::Timespec as u64,
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                submit as libc::c_uint,
+                want as libc::c_uint,
+                ENTER_GETEVENTS | ENTER_EXT_ARG,
+                &arg as *const GeteventsArg,
+                mem::size_of::<GeteventsArg>(),
+            )
+        };
+
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+
+            // Timing out isn't an error; the caller just finds fewer completions.
+            if err.raw_os_error() != Some(libc::ETIME) {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Take the next completion that's ready, handling repeated tasks and provided buffers.
+    fn reap(&mut self) -> Option<(TaskId, CompletionType)> {
+        let entry = loop {
+            if let Some(timer) = self.timers.expired() {
+                let expired = completion::Expired { timer };
+                return Some((TICK as TaskId, CompletionType::Expired(expired)));
+            }
+
+            let entry = self.completions.next()?;
+            match entry.user_data() {
+                WAKE | TICK_UPDATE => {}
+                TICK => self.ticked(),
+                _ => break entry,
             }
         };
 
         let ret = entry.result();
+        let flags = entry.flags();
         let id = entry.user_data() as TaskId;
-        let task = self.tasks.remove(id);
-        let completion = CompletionType::new(task, ret);
 
-        Ok((id, completion))
+        let mut completion = if self.repeats(id, flags, ret) {
+            // Keep the task around until the final completion.
+            CompletionType::more(&mut self.tasks[id], ret)
+        } else {
+            self.handles.complete(id);
+            self.chains.complete(id, ret);
+            CompletionType::new(self.tasks.remove(id), ret)
+        };
+
+        match completion {
+            CompletionType::ReadProvided(ref mut read) if flags & CQE_F_BUFFER != 0 => {
+                // Hand over the buffer that the kernel picked.
+                let id = (flags >> CQE_BUFFER_SHIFT) as usize;
+                read.buffer = self.provided.remove(&id);
+            }
+            CompletionType::RecvMulti(ref mut recv) if flags & CQE_F_BUFFER != 0 => {
+                let id = (flags >> CQE_BUFFER_SHIFT) as usize;
+                recv.buffer = self.provided.remove(&id);
+            }
+            CompletionType::Provide(ref provide) if provide.result.is_err() => {
+                // The kernel never took the buffer, so put it back in the pool.
+                if let Some(buffer) = self.provided.remove(&(provide.task.id as usize)) {
+                    self.buffers.give(buffer);
+                }
+            }
+            _ => {}
+        }
+
+        Some((id, completion))
     }
 
-    pub fn run_backlog(&mut self) {
-        if self.backlog.is_empty() {
+    // The wheel's timeout fired, so expire the timers that are due and wait for the next ones.
+    fn ticked(&mut self) {
+        // They fire in order, so it was the earliest.
+        if let Some(first) = self.ticks.iter().enumerate().min_by_key(|(_, tick)| **tick) {
+            self.ticks.swap_remove(first.0);
+        }
+
+        self.timers.advance(time::Instant::now());
+        self.arm_timers();
+    }
+
+    // Make sure a kernel timeout fires by the wheel's next deadline. The one in flight is moved
+    // earlier if the kernel can, otherwise another one is added.
+    // NOTE: Those timeouts all read the same timespec when they're submitted, which only ever moves
+    // earlier while one is waiting to be, so at worst it fires early.
+    fn arm_timers(&mut self) {
+        let next = match self.timers.next() {
+            Some(next) => next,
+            None => return,
+        };
+
+        if self.ticks.iter().any(|tick| *tick <= next) {
             return;
         }
 
-        while !self.submissions.is_full() {
-            let entry = match self.backlog.pop_front() {
-                Some(entry) => entry,
-                None => return,
-            };
+        *self.tick = task::monotonic(next);
+
+        let entry = if !self.ticks.is_empty() && self.capabilities.timeout_update {
+            self.ticks.clear();
+            task::timeout_update(TICK, &self.tick, types::TimeoutFlags::ABS).user_data(TICK_UPDATE)
+        } else {
+            opcode::Timeout::new(&*self.tick)
+                .flags(types::TimeoutFlags::ABS)
+                .build()
+                .user_data(TICK)
+        };
+
+        self.ticks.push(next);
+        self.push(entry);
+    }
+
+    // Cancel every in-flight task and wait for them all to complete, discarding the results.
+    // Afterwards the kernel no longer references any task's sockets or buffers.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.closing = true;
+
+        let ids: Vec<TaskId> = self.tasks.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            self.cancel(id);
+        }
+
+        while !self.tasks.is_empty() {
+            self.wait()?;
+        }
+
+        Ok(())
+    }
+
+    // Submit new tasks without waiting for any. With SQPOLL they're made visible to the polling
+    // thread, waking it only if it went to sleep after idling, and nothing is entered otherwise.
+    fn publish(&mut self) -> Result<()> {
+        self.submissions.sync();
+        self.cancel_dropped();
+        self.run_backlog();
+        self.submissions.sync();
+
+        if let Some(err) = self.error.take() {
+            return Err(err.into());
+        }
+
+        if !self.submissions.is_empty() {
+            self.submitter.submit()?;
+        }
+
+        Ok(())
+    }
+
+    // Move what fits from the backlog to the queue, followed by any chain that was left open.
+    pub fn run_backlog(&mut self) {
+        self.move_backlog();
+
+        // It's pushed as it is, since its tasks might be waited on.
+        if !self.linking.is_empty() {
+            self.push_linked();
+        }
+    }
+
+    fn move_backlog(&mut self) {
+        // Linked tasks are only moved over together, so a chain is never split across submissions.
+        while let Some(len) = self.backlog_chain() {
+            let room = self.room();
+
+            // A chain longer than the queue was never going to fit, so it's split anyway.
+            if len > room && room < self.submissions.capacity() {
+                return;
+            }
 
-            unsafe {
-                // won't fail
-                self.submissions.push(entry).ok();
+            for _ in 0..cmp::min(len, room) {
+                let entry = self.backlog.pop_front().unwrap();
+
+                unsafe {
+                    if self.submissions.push(&entry).is_err() {
+                        self.backlog.push_front(entry);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // The number of entries in the chain at the front of the backlog, or None if it's empty.
+    fn backlog_chain(&self) -> Option<usize> {
+        let mut len = 0;
+
+        for entry in self.backlog.iter() {
+            len += 1;
+
+            if !task::is_linked(entry) {
+                break;
             }
         }
+
+        match len {
+            0 => None,
+            len => Some(len),
+        }
+    }
+}
+
+// The completions that were ready, taken in order without waiting for more.
+pub struct Ready<'r, 'a> {
+    runtime: &'r mut Runtime<'a>,
+}
+
+impl Iterator for Ready<'_, '_> {
+    type Item = (TaskId, CompletionType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.reap()
     }
 }
@@ -0,0 +1,60 @@
+use std::io;
+use std::os::unix::io::RawFd;
+
+use io_uring::IoUring;
+
+// How to create a ring, where Setup::new(entries) matches IoUring::new(entries).
+#[derive(Clone, Debug, Default)]
+pub struct Setup {
+    pub entries: u32,
+    pub completions: u32,        // or 0 for twice the entries
+    pub sqpoll: Option<u32>,     // poll for tasks, sleeping after this idle ms
+    pub sqpoll_cpu: Option<u32>, // pin the polling thread to this CPU
+    pub coop_taskrun: bool,      // run completion work when entering the kernel
+    pub single_issuer: bool,     // only the thread that created it submits
+    pub defer_taskrun: bool,     // run completion work only while waiting
+    pub attach: Option<RawFd>,   // share this ring's async and polling threads
+}
+
+impl Setup {
+    pub fn new(entries: u32) -> Self {
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+
+    pub fn build(&self) -> io::Result<IoUring> {
+        let mut builder = IoUring::builder();
+
+        if let Some(idle) = self.sqpoll {
+            builder.setup_sqpoll(idle);
+        }
+
+        if let Some(cpu) = self.sqpoll_cpu {
+            builder.setup_sqpoll_cpu(cpu);
+        }
+
+        if self.completions > 0 {
+            builder.setup_cqsize(self.completions);
+        }
+
+        if let Some(fd) = self.attach {
+            builder.setup_attach_wq(fd);
+        }
+
+        if self.coop_taskrun {
+            builder.setup_coop_taskrun();
+        }
+
+        if self.single_issuer {
+            builder.setup_single_issuer();
+        }
+
+        if self.defer_taskrun {
+            builder.setup_defer_taskrun();
+        }
+
+        builder.build(self.entries)
+    }
+}
@@ -0,0 +1,17 @@
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+
+// Block the signals on the current thread and receive them from a file descriptor instead.
+// NOTE: Other threads must block the signals too, otherwise they may be delivered there.
+pub fn listen(signals: &[Signal]) -> anyhow::Result<SignalFd> {
+    let mut mask = SigSet::empty();
+    for signal in signals {
+        mask.add(*signal);
+    }
+
+    mask.thread_block()?;
+
+    let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC)?;
+
+    Ok(fd)
+}
@@ -1,21 +1,146 @@
 use std::os::unix::io::AsRawFd;
-use std::{mem, net, ops, ptr, time};
+use std::os::unix::net::UnixListener;
+use std::{fs, mem, net, ops, ptr, time};
 
-use io_uring::opcode::{self, types};
-use io_uring::squeue::Entry;
+use io_uring::squeue::{Entry, Flags};
+use io_uring::{opcode, types};
 
 use enum_dispatch::enum_dispatch;
+use nix::sys::signalfd::{self, SignalFd};
+use nix::sys::socket;
+
+use super::{buffer, files, tcp};
+
+// Build the entry with the socket's slot in the registered file table, which saves the kernel from
+// looking up the descriptor each time, or with the descriptor if it isn't registered.
+macro_rules! with_socket {
+    ($socket:expr, |$fd:ident| $entry:expr) => {
+        match $socket.target() {
+            tcp::Target::Fixed(slot) => {
+                let $fd = types::Fixed(slot);
+                $entry
+            }
+            tcp::Target::Fd(fd) => {
+                let $fd = types::Fd(fd);
+                $entry
+            }
+        }
+    };
+}
+
+// Set in the accept entry's ioprio to keep accepting connections until cancelled or failed.
+const ACCEPT_MULTISHOT: u16 = 1;
+
+// Set in the recv entry's ioprio to keep receiving into provided buffers until EOF or failure.
+const RECV_MULTISHOT: u16 = 1 << 1;
+
+// Set in the send entry's ioprio when sending from a registered buffer, given by buf_index.
+const RECVSEND_FIXED_BUF: u16 = 1 << 2;
+
+// Set in the timeout remove entry's flags to move the timeout's deadline instead.
+const TIMEOUT_UPDATE: u32 = 1 << 1;
+
+// The kernel's submission entry, for the fields that io_uring doesn't let us set yet.
+#[repr(C)]
+struct RawEntry {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32, // one more than the slot to accept into, or 0 for a descriptor
+    pad: [u64; 2],
+}
 
-use super::{buffer, tcp};
+fn patch<F: FnOnce(&mut RawEntry)>(entry: Entry, f: F) -> Entry {
+    // NOTE: Entry is a transparent wrapper around the kernel's struct, so they have the same layout.
+    let mut raw: RawEntry = unsafe { mem::transmute(entry) };
+    f(&mut raw);
+    unsafe { mem::transmute(raw) }
+}
+
+// Returns true if the entry is linked to the next one, so they have to be submitted together.
+pub fn is_linked(entry: &Entry) -> bool {
+    let raw = unsafe { &*(entry as *const Entry as *const RawEntry) };
+    raw.flags & (Flags::IO_LINK | Flags::IO_HARDLINK).bits() != 0
+}
 
 // Accept a TCP connection.
 pub struct Accept {
     pub socket: net::TcpListener,
+    pub repeat: bool,    // keep accepting until cancelled or failed
+    pub multishot: bool, // repeated by the kernel instead of resubmitted by the runtime
 }
 
 pub type TaskId = usize;
 
 impl Task for Accept {
+    fn entry(&mut self) -> Entry {
+        let entry = opcode::Accept::new(
+            types::Fd(self.socket.as_raw_fd()),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+        .build();
+
+        if self.repeat && self.multishot {
+            patch(entry, |raw| raw.ioprio = ACCEPT_MULTISHOT)
+        } else {
+            entry
+        }
+    }
+}
+
+// Accept a TCP connection straight into a slot in the file table, without a descriptor.
+pub struct AcceptDirect {
+    pub socket: net::TcpListener,
+    pub slot: Option<files::Slot>, // accepts a descriptor instead if None
+    pub addr: Box<(libc::sockaddr_storage, libc::socklen_t)>, // boxed so the kernel sees a stable address
+}
+
+impl AcceptDirect {
+    pub fn new(socket: net::TcpListener, slot: Option<files::Slot>) -> Self {
+        let addr = (
+            unsafe { mem::zeroed() },
+            mem::size_of::<libc::sockaddr_storage>() as _,
+        );
+
+        Self {
+            socket,
+            slot,
+            addr: Box::new(addr),
+        }
+    }
+}
+
+impl Task for AcceptDirect {
+    fn entry(&mut self) -> Entry {
+        let entry = opcode::Accept::new(
+            types::Fd(self.socket.as_raw_fd()),
+            &mut self.addr.0 as *mut _ as _,
+            &mut self.addr.1,
+        )
+        .build();
+
+        match &self.slot {
+            Some(slot) => patch(entry, |raw| raw.file_index = slot.index() + 1),
+            None => entry,
+        }
+    }
+}
+
+// Accept a Unix socket connection.
+pub struct AcceptUnix {
+    pub socket: UnixListener,
+}
+
+impl Task for AcceptUnix {
     fn entry(&mut self) -> Entry {
         opcode::Accept::new(
             types::Fd(self.socket.as_raw_fd()),
@@ -39,11 +164,13 @@ impl Task for Cancel {
 // Dial a TCP connection to the given address.
 pub struct Connect {
     pub socket: tcp::Reader,
-    addr: Box<net::SocketAddr>,
+    addr: Box<socket::SockAddr>, // boxed so the kernel sees a stable address
 }
 
 impl Connect {
     pub fn new(socket: tcp::Reader, addr: net::SocketAddr) -> Self {
+        let addr = socket::SockAddr::new_inet(socket::InetAddr::from_std(&addr));
+
         Self {
             socket,
             addr: Box::new(addr),
@@ -53,18 +180,38 @@ impl Connect {
 
 impl Task for Connect {
     fn entry(&mut self) -> Entry {
-        let (addr, size) = match *self.addr {
-            net::SocketAddr::V4(ref a) => (
-                a as *const _ as *const _,
-                mem::size_of_val(a) as libc::socklen_t,
-            ),
-            net::SocketAddr::V6(ref a) => (
-                a as *const _ as *const _,
-                mem::size_of_val(a) as libc::socklen_t,
-            ),
-        };
+        // NOTE: std::net::SocketAddr is not guaranteed to match the C layout, so convert.
+        let (addr, size) = self.addr.as_ffi_pair();
 
-        opcode::Connect::new(types::Fd(self.socket.as_raw_fd()), addr, size).build()
+        with_socket!(self.socket, |fd| {
+            opcode::Connect::new(fd, addr, size).build()
+        })
+    }
+}
+
+// Wait for an eventfd to be notified, resetting its counter.
+pub struct Event {
+    pub fd: fs::File,
+    pub count: Box<u64>, // boxed so the kernel sees a stable address
+}
+
+impl Event {
+    pub fn new(fd: fs::File) -> Self {
+        Self {
+            fd,
+            count: Box::new(0),
+        }
+    }
+}
+
+impl Task for Event {
+    fn entry(&mut self) -> Entry {
+        opcode::Read::new(
+            types::Fd(self.fd.as_raw_fd()),
+            &mut *self.count as *mut u64 as _,
+            mem::size_of::<u64>() as _,
+        )
+        .build()
     }
 }
 
@@ -76,12 +223,77 @@ pub struct Read {
 
 impl Task for Read {
     fn entry(&mut self) -> Entry {
-        opcode::Read::new(
-            types::Fd(self.socket.as_raw_fd()),
-            self.buffer.as_mut_ptr(),
-            self.buffer.len() as _,
-        )
-        .build()
+        with_socket!(self.socket, |fd| {
+            opcode::Read::new(fd, self.buffer.as_mut_ptr(), self.buffer.len() as _).build()
+        })
+    }
+}
+
+// Read from a TCP socket into a buffer that the kernel picks from the group once data arrives.
+pub struct ReadProvided {
+    pub socket: tcp::Reader,
+    pub group: u16,
+    pub len: u32, // the most to read, capped to the buffer size
+}
+
+impl ReadProvided {
+    pub fn new(socket: tcp::Reader, len: usize) -> Self {
+        Self {
+            socket,
+            group: buffer::PROVIDED_GROUP,
+            len: len as _,
+        }
+    }
+}
+
+impl Task for ReadProvided {
+    fn entry(&mut self) -> Entry {
+        with_socket!(self.socket, |fd| {
+            opcode::Read::new(fd, ptr::null_mut(), self.len)
+                .buf_group(self.group)
+                .build()
+                .flags(Flags::BUFFER_SELECT)
+        })
+    }
+}
+
+// Keep receiving from a TCP socket into buffers that the kernel picks from the group, completing
+// once for each chunk until EOF, failure, or running out of buffers.
+pub struct RecvMulti {
+    pub socket: tcp::Reader,
+    pub group: u16,
+    pub len: u32,        // the most to read at once, when resubmitted by the runtime
+    pub multishot: bool, // repeated by the kernel instead of resubmitted by the runtime
+}
+
+impl RecvMulti {
+    pub fn new(socket: tcp::Reader, len: usize, multishot: bool) -> Self {
+        Self {
+            socket,
+            group: buffer::PROVIDED_GROUP,
+            len: len as _,
+            multishot,
+        }
+    }
+}
+
+impl Task for RecvMulti {
+    fn entry(&mut self) -> Entry {
+        // Multishot receives use the size of whichever buffer is picked.
+        let len = if self.multishot { 0 } else { self.len };
+
+        let entry = with_socket!(self.socket, |fd| {
+            opcode::Recv::new(fd, ptr::null_mut(), len)
+                .buf_group(self.group)
+                .build()
+                .flags(Flags::BUFFER_SELECT)
+        });
+
+        if self.multishot {
+            patch(entry, |raw| raw.ioprio = RECV_MULTISHOT)
+        } else {
+            entry
+        }
     }
 }
 
@@ -92,37 +304,316 @@ pub struct ReadFixed {
 
 impl Task for ReadFixed {
     fn entry(&mut self) -> Entry {
-        opcode::ReadFixed::new(
-            types::Fd(self.socket.as_raw_fd()),
-            self.buffer.as_mut_ptr(),
-            self.buffer.len() as _,
-            self.buffer.id() as _,
+        with_socket!(self.socket, |fd| {
+            opcode::ReadFixed::new(
+                fd,
+                self.buffer.as_mut_ptr(),
+                self.buffer.len() as _,
+                self.buffer.id() as _,
+            )
+            .build()
+        })
+    }
+}
+
+// Wait for a file descriptor to become ready, such as POLLIN before reading.
+// NOTE: This doesn't hold the socket, so it must be kept open by another task or the caller.
+pub struct Poll {
+    pub target: tcp::Target,
+    pub events: libc::c_short,
+}
+
+impl Task for Poll {
+    fn entry(&mut self) -> Entry {
+        match self.target {
+            tcp::Target::Fixed(slot) => {
+                opcode::PollAdd::new(types::Fixed(slot), self.events as _).build()
+            }
+            tcp::Target::Fd(fd) => opcode::PollAdd::new(types::Fd(fd), self.events as _).build(),
+        }
+    }
+}
+
+// Give a buffer to the kernel, for reads in the group to pick from.
+// NOTE: The runtime owns the buffer until it's picked, so this only holds its address.
+pub struct Provide {
+    pub group: u16,
+    pub id: u16,
+    addr: *mut u8,
+    len: i32,
+}
+
+impl Provide {
+    pub fn new(group: u16, buffer: &mut buffer::Fixed) -> Self {
+        Self {
+            group,
+            id: buffer.id() as _,
+            addr: buffer.as_mut_ptr(),
+            len: buffer.len() as _,
+        }
+    }
+}
+
+impl Task for Provide {
+    fn entry(&mut self) -> Entry {
+        opcode::ProvideBuffers::new(self.addr, self.len, 1, self.group, self.id).build()
+    }
+}
+
+// Wait for the next signal to arrive on a signalfd.
+pub struct Signal {
+    pub fd: SignalFd,
+    pub info: Box<signalfd::siginfo>, // boxed so the kernel sees a stable address
+}
+
+impl Signal {
+    pub fn new(fd: SignalFd) -> Self {
+        Self {
+            fd,
+            info: Box::new(unsafe { mem::zeroed() }),
+        }
+    }
+}
+
+impl Task for Signal {
+    fn entry(&mut self) -> Entry {
+        opcode::Read::new(
+            types::Fd(self.fd.as_raw_fd()),
+            &mut *self.info as *mut _ as _,
+            mem::size_of::<signalfd::siginfo>() as _,
         )
         .build()
     }
 }
 
-pub struct Timeout {
-    duration: types::Timespec,
+// Wait for the duration to elapse, or until the deadline.
+pub struct Sleep {
+    duration: Box<types::Timespec>, // boxed so the kernel sees a stable address
+    flags: types::TimeoutFlags,     // ABS if the duration is a deadline on the monotonic clock
+    count: u32,                     // finish early once this many other tasks complete, if set
 }
 
-impl Timeout {
+impl Sleep {
     pub fn new(duration: time::Duration) -> Self {
-        let duration = types::Timespec {
-            tv_sec: duration.as_secs() as _,
-            tv_nsec: duration.subsec_nanos() as _,
+        Self {
+            duration: Box::new(timespec(duration)),
+            flags: types::TimeoutFlags::empty(),
+            count: 0,
+        }
+    }
+
+    // Wait until the deadline, which doesn't drift however long the task waits to be submitted.
+    pub fn until(deadline: time::Instant) -> Self {
+        Self {
+            duration: Box::new(monotonic(deadline)),
+            flags: types::TimeoutFlags::ABS,
+            count: 0,
+        }
+    }
+
+    // Also finish once count other tasks have completed, whichever comes first.
+    pub fn count(mut self, count: u32) -> Self {
+        self.count = count;
+        self
+    }
+}
+
+impl Task for Sleep {
+    fn entry(&mut self) -> Entry {
+        opcode::Timeout::new(&*self.duration)
+            .count(self.count)
+            .flags(self.flags)
+            .build()
+    }
+}
+
+// Send to a TCP socket without copying the data, which the kernel reads straight from the buffer
+// until it's been transmitted. The task completes twice: once with the result, returning the
+// socket, and again with a notification once the kernel is done with the buffer, returning the task.
+// NOTE: Zero-copy costs more than copying for small sends, since the pages have to be pinned.
+pub struct SendZc {
+    pub socket: Option<tcp::Writer>, // taken once the result arrives
+    pub buffer: buffer::Buffer,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SendZc {
+    // IORING_OP_SEND_ZC, which io_uring doesn't support yet.
+    pub const CODE: u8 = 47;
+
+    pub fn new<R>(socket: tcp::Writer, buffer: buffer::Buffer, range: R) -> Self
+    where
+        R: ops::RangeBounds<usize>,
+    {
+        let start = match range.start_bound() {
+            ops::Bound::Included(n) => *n,
+            ops::Bound::Excluded(n) => n + 1,
+            ops::Bound::Unbounded => 0,
         };
 
-        Self { duration }
+        let end = match range.end_bound() {
+            ops::Bound::Included(n) => n + 1,
+            ops::Bound::Excluded(n) => *n,
+            ops::Bound::Unbounded => buffer.len(),
+        };
+
+        Self {
+            socket: Some(socket),
+            buffer,
+            start,
+            end,
+        }
+    }
+}
+
+impl Task for SendZc {
+    fn entry(&mut self) -> Entry {
+        let socket = self.socket.as_ref().expect("send without a socket");
+        let id = self.buffer.id();
+        let buffer = &self.buffer[self.start..self.end];
+
+        // Wait to send everything, rather than completing after a short send.
+        let entry = with_socket!(socket, |fd| {
+            opcode::Send::new(fd, buffer.as_ptr(), buffer.len() as _)
+                .flags(libc::MSG_WAITALL)
+                .build()
+        });
+
+        patch(entry, |raw| {
+            raw.opcode = Self::CODE;
+
+            if let Some(id) = id {
+                raw.ioprio = RECVSEND_FIXED_BUF;
+                raw.buf_index = id as _;
+            }
+        })
+    }
+}
+
+// Move data between a socket and a kernel pipe, without copying it through userspace.
+pub enum Splice {
+    // From the socket into the pipe.
+    In {
+        socket: tcp::Reader,
+        pipe: fs::File, // write end
+        len: u32,
+    },
+    // From the pipe into the socket.
+    Out {
+        pipe: fs::File, // read end
+        socket: tcp::Writer,
+        len: u32,
+    },
+}
+
+impl Task for Splice {
+    fn entry(&mut self) -> Entry {
+        // Neither end is seekable, so the offsets are -1.
+        let entry = match self {
+            Splice::In { socket, pipe, len } => with_socket!(socket, |fd| {
+                opcode::Splice::new(
+                    fd,
+                    -1i64 as _,
+                    types::Fd(pipe.as_raw_fd()),
+                    -1i64 as _,
+                    *len,
+                )
+            }),
+            Splice::Out { pipe, socket, len } => with_socket!(socket, |fd| {
+                opcode::Splice::new(
+                    types::Fd(pipe.as_raw_fd()),
+                    -1i64 as _,
+                    fd,
+                    -1i64 as _,
+                    *len,
+                )
+            }),
+        };
+
+        // Splices run on a kernel thread that would block waiting for the socket, and can't be
+        // timed out while doing so, so they return EAGAIN instead. Poll the socket first.
+        entry.flags(libc::SPLICE_F_NONBLOCK).build()
+    }
+}
+
+// Cancel the previous linked task if it hasn't finished within the duration.
+pub struct Timeout {
+    duration: Box<types::Timespec>, // boxed so the kernel sees a stable address
+}
+
+impl Timeout {
+    pub fn new(duration: time::Duration) -> Self {
+        Self {
+            duration: Box::new(timespec(duration)),
+        }
     }
 }
 
 impl Task for Timeout {
     fn entry(&mut self) -> Entry {
-        opcode::LinkTimeout::new(&self.duration).build()
+        opcode::LinkTimeout::new(&*self.duration).build()
     }
 }
 
+// Remove a sleep before it elapses, which then fails with ECANCELED.
+pub struct TimeoutRemove {
+    pub id: TaskId, // the sleep
+}
+
+impl Task for TimeoutRemove {
+    fn entry(&mut self) -> Entry {
+        opcode::TimeoutRemove::new(self.id as _).build()
+    }
+}
+
+// Move the deadline of a sleep that hasn't elapsed yet, as if it had started with the new one.
+// NOTE: Requires Linux 5.11, see Capabilities::timeout_update.
+pub struct TimeoutUpdate {
+    pub id: TaskId, // the sleep
+    duration: Box<types::Timespec>,
+    flags: types::TimeoutFlags,
+}
+
+impl TimeoutUpdate {
+    pub fn new(id: TaskId, duration: time::Duration) -> Self {
+        Self {
+            id,
+            duration: Box::new(timespec(duration)),
+            flags: types::TimeoutFlags::empty(),
+        }
+    }
+
+    pub fn until(id: TaskId, deadline: time::Instant) -> Self {
+        Self {
+            id,
+            duration: Box::new(monotonic(deadline)),
+            flags: types::TimeoutFlags::ABS,
+        }
+    }
+}
+
+impl Task for TimeoutUpdate {
+    fn entry(&mut self) -> Entry {
+        timeout_update(self.id as _, &self.duration, self.flags)
+    }
+}
+
+// Builds the entry that moves the deadline of the timeout with the user data.
+pub(super) fn timeout_update(
+    user_data: u64,
+    timespec: &types::Timespec,
+    flags: types::TimeoutFlags,
+) -> Entry {
+    let entry = opcode::TimeoutRemove::new(user_data).flags(flags).build();
+
+    // The new timespec goes where a read would have its offset.
+    patch(entry, |raw| {
+        raw.op_flags |= TIMEOUT_UPDATE;
+        raw.off = timespec as *const types::Timespec as u64;
+    })
+}
+
 // Write to a TCP socket.
 pub struct Write {
     pub socket: tcp::Writer,   // write data to this file descriptor
@@ -161,12 +652,9 @@ impl Task for Write {
     fn entry(&mut self) -> Entry {
         let buffer = &mut self.buffer[self.start..self.end];
 
-        opcode::Write::new(
-            types::Fd(self.socket.as_raw_fd()),
-            buffer.as_mut_ptr(),
-            buffer.len() as _,
-        )
-        .build()
+        with_socket!(self.socket, |fd| {
+            opcode::Write::new(fd, buffer.as_mut_ptr(), buffer.len() as _).build()
+        })
     }
 }
 
@@ -209,16 +697,31 @@ impl Task for WriteFixed {
         let id = self.buffer.id();
         let buffer = &mut self.buffer[self.start..self.end];
 
-        opcode::WriteFixed::new(
-            types::Fd(self.socket.as_raw_fd()),
-            buffer.as_mut_ptr(),
-            buffer.len() as _,
-            id as _,
-        )
-        .build()
+        with_socket!(self.socket, |fd| {
+            opcode::WriteFixed::new(fd, buffer.as_mut_ptr(), buffer.len() as _, id as _).build()
+        })
     }
 }
 
+pub(super) fn timespec(duration: time::Duration) -> types::Timespec {
+    types::Timespec::new()
+        .sec(duration.as_secs())
+        .nsec(duration.subsec_nanos())
+}
+
+// The deadline on the monotonic clock, which the kernel compares absolute timeouts against.
+pub(super) fn monotonic(deadline: time::Instant) -> types::Timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    let now = time::Duration::new(now.tv_sec as _, now.tv_nsec as _);
+    timespec(now + deadline.saturating_duration_since(time::Instant::now()))
+}
+
 #[enum_dispatch]
 pub trait Task {
     fn entry(&mut self) -> Entry;
@@ -227,11 +730,24 @@ pub trait Task {
 #[enum_dispatch(Task)]
 pub enum TaskType {
     Accept,
+    AcceptDirect,
+    AcceptUnix,
     Cancel,
     Connect,
+    Event,
+    Poll,
+    Provide,
     Read,
     ReadFixed,
+    ReadProvided,
+    RecvMulti,
+    SendZc,
+    Signal,
+    Sleep,
+    Splice,
     Timeout,
+    TimeoutRemove,
+    TimeoutUpdate,
     Write,
     WriteFixed,
 }
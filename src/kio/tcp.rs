@@ -1,47 +1,246 @@
-use std::net;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::rc::Rc;
+use std::{io, mem, net};
+
+use nix::sys::socket::{self, sockopt};
+
+use super::files;
+
+// How tasks refer to a socket.
+#[derive(Clone, Copy)]
+pub enum Target {
+    Fd(RawFd),
+    Fixed(u32), // the slot in the ring's file table
+}
+
+// The socket shared by both halves, closed once both are dropped.
+// NOTE: Sockets accepted directly into the file table have no descriptor, so they can't be shut
+// down halfway; they're only closed once both halves are dropped and the slot is cleared.
+struct Shared {
+    stream: Option<net::TcpStream>, // None if only in the file table
+    slot: Option<files::Slot>,      // set if registered with the ring
+}
+
+impl Shared {
+    fn target(&self) -> Target {
+        match (&self.slot, &self.stream) {
+            (Some(slot), _) => Target::Fixed(slot.index()),
+            (None, Some(stream)) => Target::Fd(stream.as_raw_fd()),
+            (None, None) => unreachable!("socket without a descriptor or slot"),
+        }
+    }
+
+    fn shutdown(&self, how: net::Shutdown) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(how);
+        }
+    }
+}
 
 pub struct Reader {
-    inner: Rc<net::TcpStream>,
+    inner: Rc<Shared>,
 }
 
-impl std::ops::Deref for Reader {
-    type Target = net::TcpStream;
+impl Reader {
+    // The index in the ring's file table, if registered.
+    pub fn slot(&self) -> Option<u32> {
+        self.inner.slot.as_ref().map(files::Slot::index)
+    }
+
+    // The socket's slot if registered, otherwise its descriptor.
+    pub fn target(&self) -> Target {
+        self.inner.target()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    // The underlying socket, unless it was accepted directly into the file table.
+    pub fn stream(&self) -> Option<&net::TcpStream> {
+        self.inner.stream.as_ref()
     }
 }
 
 impl std::ops::Drop for Reader {
     fn drop(&mut self) {
-        let _ = self.shutdown(net::Shutdown::Read);
+        self.inner.shutdown(net::Shutdown::Read);
     }
 }
 
 pub struct Writer {
-    inner: Rc<net::TcpStream>,
+    inner: Rc<Shared>,
 }
 
-impl std::ops::Deref for Writer {
-    type Target = net::TcpStream;
+impl Writer {
+    // The index in the ring's file table, if registered.
+    pub fn slot(&self) -> Option<u32> {
+        self.inner.slot.as_ref().map(files::Slot::index)
+    }
+
+    // The socket's slot if registered, otherwise its descriptor.
+    pub fn target(&self) -> Target {
+        self.inner.target()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    // The underlying socket, unless it was accepted directly into the file table.
+    pub fn stream(&self) -> Option<&net::TcpStream> {
+        self.inner.stream.as_ref()
     }
 }
 
 impl std::ops::Drop for Writer {
     fn drop(&mut self) {
-        let _ = self.shutdown(net::Shutdown::Write);
+        self.inner.shutdown(net::Shutdown::Write);
     }
 }
 
 pub fn split(stream: net::TcpStream) -> (Reader, Writer) {
-    let inner = Rc::new(stream);
+    split_registered(stream, None)
+}
+
+// Split a socket that was registered with the ring, freeing the slot once both halves are dropped.
+pub fn split_registered(stream: net::TcpStream, slot: Option<files::Slot>) -> (Reader, Writer) {
+    split_shared(Shared {
+        stream: Some(stream),
+        slot,
+    })
+}
+
+// Split a socket that was accepted directly into the file table, closed once the slot is cleared.
+pub fn split_direct(slot: files::Slot) -> (Reader, Writer) {
+    split_shared(Shared {
+        stream: None,
+        slot: Some(slot),
+    })
+}
+
+fn split_shared(shared: Shared) -> (Reader, Writer) {
+    let inner = Rc::new(shared);
     let reader = Reader {
         inner: Rc::clone(&inner),
     };
     let writer = Writer { inner };
     (reader, writer)
 }
+
+// Create an unconnected TCP socket with the same address family as the given address.
+pub fn socket(addr: &net::SocketAddr) -> anyhow::Result<net::TcpStream> {
+    // We need to use the nix package because there's no way to do this in the stdlib.
+    let fd = socket::socket(
+        family(addr),
+        socket::SockType::Stream,
+        socket::SockFlag::SOCK_CLOEXEC,
+        socket::SockProtocol::Tcp,
+    )?;
+
+    Ok(unsafe { net::TcpStream::from_raw_fd(fd) })
+}
+
+// Bind and listen on the given address.
+// IPv6 listeners will also accept IPv4 connections unless v6_only is set.
+// Set reuse_port to let multiple listeners share the address, with connections spread between them.
+pub fn listen(
+    addr: &net::SocketAddr,
+    v6_only: bool,
+    reuse_port: bool,
+) -> anyhow::Result<net::TcpListener> {
+    let fd = socket::socket(
+        family(addr),
+        socket::SockType::Stream,
+        socket::SockFlag::SOCK_CLOEXEC,
+        socket::SockProtocol::Tcp,
+    )?;
+
+    // Take ownership immediately so the fd is closed on error.
+    let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)?;
+
+    if reuse_port {
+        socket::setsockopt(fd, sockopt::ReusePort, &true)?;
+    }
+
+    if addr.is_ipv6() {
+        // nix doesn't expose IPV6_V6ONLY, so use libc directly.
+        let value = v6_only as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    let sockaddr = socket::SockAddr::new_inet(socket::InetAddr::from_std(addr));
+    socket::bind(fd, &sockaddr)?;
+    socket::listen(fd, 1024)?;
+
+    Ok(listener)
+}
+
+// Route each new connection to the listener at the same index as the CPU that received it.
+// Applies to every listener sharing the address with SO_REUSEPORT, in the order they were bound.
+pub fn steer(listener: &net::TcpListener) -> anyhow::Result<()> {
+    // Missing from libc for this target.
+    const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+
+    // A = current CPU; return A
+    let mut filter = [
+        libc::sock_filter {
+            code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as _,
+            jt: 0,
+            jf: 0,
+            k: (libc::SKF_AD_OFF + libc::SKF_AD_CPU) as _,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_A) as _,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        },
+    ];
+
+    let program = libc::sock_fprog {
+        len: filter.len() as _,
+        filter: filter.as_mut_ptr(),
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_CBPF,
+            &program as *const _ as *const libc::c_void,
+            mem::size_of_val(&program) as libc::socklen_t,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+fn family(addr: &net::SocketAddr) -> socket::AddressFamily {
+    match addr {
+        net::SocketAddr::V4(_) => socket::AddressFamily::Inet,
+        net::SocketAddr::V6(_) => socket::AddressFamily::Inet6,
+    }
+}
+
+// Reset the connection instead of closing it gracefully once the socket is closed.
+pub fn abort(fd: RawFd) -> anyhow::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+
+    socket::setsockopt(fd, sockopt::Linger, &linger)?;
+
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::time;
+
+use slab::Slab;
+
+pub type TimerId = usize;
+
+// Each level has 64 slots, each one covering all the slots of the level below.
+const BITS: u32 = 6;
+const SLOTS: u64 = 1 << BITS;
+const LEVELS: usize = 10;
+
+// The ticks that fit in the wheel, decades even at a nanosecond resolution.
+const MAX: u64 = 1 << (BITS * LEVELS as u32);
+
+// A hierarchical timer wheel, so timers are started, reset and cancelled without searching, and
+// any number of them share the single kernel timeout for the next slot.
+// Deadlines are rounded up to the resolution, so a timer never expires early.
+pub struct Wheel {
+    start: time::Instant, // tick zero
+    resolution: time::Duration,
+    now: u64, // every slot before this tick has expired
+
+    timers: Slab<Timer>,
+    slots: Vec<[Option<TimerId>; SLOTS as usize]>, // the first timer in each slot, by level
+    occupied: [u64; LEVELS],                       // a bit for each slot with timers, by level
+    expired: VecDeque<TimerId>,                    // not yet taken
+}
+
+// Timers in a slot form a list, so one is removed without searching the slot.
+struct Timer {
+    tick: u64,
+    slot: Option<(usize, u64)>, // the level and slot, or None once expired
+    prev: Option<TimerId>,
+    next: Option<TimerId>,
+}
+
+impl Wheel {
+    pub fn new(resolution: time::Duration) -> Self {
+        assert!(!resolution.is_zero(), "resolution must not be zero");
+
+        Self {
+            start: time::Instant::now(),
+            resolution,
+            now: 0,
+
+            timers: Slab::new(),
+            slots: vec![[None; SLOTS as usize]; LEVELS],
+            occupied: [0; LEVELS],
+            expired: VecDeque::new(),
+        }
+    }
+
+    // The number of timers, including those that expired but haven't been taken yet.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    // Start a timer, returning its id, which isn't reused until its expiry has been taken.
+    pub fn insert(&mut self, deadline: time::Instant) -> TimerId {
+        let id = self.timers.insert(Timer {
+            tick: self.ceil(deadline),
+            slot: None,
+            prev: None,
+            next: None,
+        });
+
+        self.link(id);
+        id
+    }
+
+    // Move the timer to the new deadline. Returns false if it already expired.
+    pub fn reset(&mut self, id: TimerId, deadline: time::Instant) -> bool {
+        if !self.is_pending(id) {
+            return false;
+        }
+
+        self.unlink(id);
+        self.timers[id].tick = self.ceil(deadline);
+        self.link(id);
+
+        true
+    }
+
+    // Stop the timer. Returns false if it already expired, in which case it's still taken.
+    pub fn remove(&mut self, id: TimerId) -> bool {
+        if !self.is_pending(id) {
+            return false;
+        }
+
+        self.unlink(id);
+        self.timers.remove(id);
+
+        true
+    }
+
+    // When the next slot is due, which may only move timers to a lower level instead of expiring
+    // them. None if there are no timers.
+    pub fn next(&self) -> Option<time::Instant> {
+        let (_, _, tick) = self.next_slot()?;
+        let nanos = tick as u128 * self.resolution.as_nanos();
+
+        // Too far away to ever be reached.
+        let nanos = u64::try_from(nanos).ok()?;
+        self.start.checked_add(time::Duration::from_nanos(nanos))
+    }
+
+    // Expire every timer due by now, to be taken with expired.
+    pub fn advance(&mut self, now: time::Instant) {
+        let target = self.floor(now);
+
+        while let Some((level, slot, tick)) = self.next_slot() {
+            if tick > target {
+                break;
+            }
+
+            self.now = tick;
+
+            let mut head = self.slots[level][slot as usize].take();
+            self.occupied[level] &= !(1 << slot);
+
+            while let Some(id) = head {
+                head = self.timers[id].next;
+
+                // Slots in the lowest level are a single tick, so their timers are due.
+                // Otherwise the timers move down, now that they're closer.
+                match level {
+                    0 => {
+                        self.timers[id].slot = None;
+                        self.expired.push_back(id);
+                    }
+                    _ => self.link(id),
+                }
+            }
+        }
+
+        self.now = self.now.max(target);
+    }
+
+    // Take the next timer that expired, freeing its id.
+    pub fn expired(&mut self) -> Option<TimerId> {
+        let id = self.expired.pop_front()?;
+        self.timers.remove(id);
+
+        Some(id)
+    }
+
+    fn is_pending(&self, id: TimerId) -> bool {
+        matches!(self.timers.get(id), Some(timer) if timer.slot.is_some())
+    }
+
+    // Add the timer to the slot for its tick. The level is picked by the highest bit that differs
+    // from now, so a timer only moves down a level once now has reached its slot.
+    fn link(&mut self, id: TimerId) {
+        let tick = self.timers[id].tick.max(self.now);
+
+        let masked = (tick ^ self.now) | (SLOTS - 1);
+        let level = ((63 - masked.leading_zeros()) / BITS) as usize;
+        let slot = (tick >> (level as u32 * BITS)) & (SLOTS - 1);
+
+        let next = self.slots[level][slot as usize].replace(id);
+        if let Some(next) = next {
+            self.timers[next].prev = Some(id);
+        }
+
+        let timer = &mut self.timers[id];
+        timer.slot = Some((level, slot));
+        timer.prev = None;
+        timer.next = next;
+
+        self.occupied[level] |= 1 << slot;
+    }
+
+    fn unlink(&mut self, id: TimerId) {
+        let timer = &self.timers[id];
+        let (level, slot) = timer.slot.unwrap();
+        let (prev, next) = (timer.prev, timer.next);
+
+        match prev {
+            Some(prev) => self.timers[prev].next = next,
+            None => self.slots[level][slot as usize] = next,
+        }
+
+        if let Some(next) = next {
+            self.timers[next].prev = prev;
+        }
+
+        if next.is_none() && prev.is_none() {
+            self.occupied[level] &= !(1 << slot);
+        }
+    }
+
+    // The first slot with timers, as its level, slot and the tick it starts at.
+    // Slots before now are always empty, and every slot in a level comes before the next level's.
+    fn next_slot(&self) -> Option<(usize, u64, u64)> {
+        for level in 0..LEVELS {
+            let shift = level as u32 * BITS;
+            let current = (self.now >> shift) & (SLOTS - 1);
+
+            let occupied = self.occupied[level] >> current;
+            if occupied == 0 {
+                continue;
+            }
+
+            let slot = current + occupied.trailing_zeros() as u64;
+
+            // The slots of a level start where its current rotation did.
+            let rotation = self.now & !((SLOTS << shift) - 1);
+            let tick = rotation + (slot << shift);
+
+            return Some((level, slot, tick.max(self.now)));
+        }
+
+        None
+    }
+
+    fn ceil(&self, deadline: time::Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.start).as_nanos();
+        let resolution = self.resolution.as_nanos();
+        let tick = nanos.div_ceil(resolution);
+
+        tick.min(MAX as u128 - 1) as u64
+    }
+
+    fn floor(&self, now: time::Instant) -> u64 {
+        let nanos = now.saturating_duration_since(self.start).as_nanos();
+        let tick = nanos / self.resolution.as_nanos();
+
+        tick.min(MAX as u128 - 1) as u64
+    }
+}
+
+impl Default for Wheel {
+    fn default() -> Self {
+        Self::new(time::Duration::from_millis(1))
+    }
+}
@@ -1 +1,4 @@
+pub mod backend;
+pub mod config;
 pub mod kio;
+pub mod proxy;
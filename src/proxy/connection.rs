@@ -0,0 +1,1291 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{cmp, fs, io, mem, net, ops, time};
+
+use slab::Slab;
+
+use crate::backend::{self, BackendId};
+use crate::config::{self, Config};
+use crate::kio::buffer::{self, Buffer};
+use crate::kio::completion;
+use crate::kio::task::{self, TaskId};
+use crate::kio::timer::TimerId;
+use crate::kio::{pipe, tcp, Kio};
+
+// The direction that data flows through a connection.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Direction {
+    Incoming, // frontend to backend
+    Outgoing, // backend to frontend
+}
+
+// The most chunks a multishot read gets ahead of the writer, before it's cancelled until they're
+// written, so a slow writer can't take every buffer.
+const QUEUED: usize = 8;
+
+// One direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    reader: Option<tcp::Reader>, // set while a write is in flight
+    writer: Option<tcp::Writer>, // set while a read is in flight
+    done: bool,                  // the reader hit EOF and the writer was shut down
+    starved: bool,               // waiting for a free buffer to read into
+    spliced: Option<Spliced>,    // set in splice mode
+    recv: Option<Recv>,          // set in multishot mode
+}
+
+// A read that stays armed, completing for each chunk, so the reader is only returned once it stops.
+// Chunks are queued while the previous one is being written.
+#[derive(Default)]
+struct Recv {
+    id: Option<TaskId>,                      // set while armed
+    queue: VecDeque<(buffer::Fixed, usize)>, // received but not yet written
+    eof: bool,                               // the reader hit EOF, so shut down once written
+}
+
+// A kernel pipe that data is spliced through, so it's never copied into userspace.
+// Each chunk is moved with a chain of linked tasks: poll the reader, splice into the pipe, poll the
+// writer, and splice out of the pipe. The splice out runs even if the splice in was short, moving
+// whatever it put in the pipe. The chain is handled once every task in it has finished.
+struct Spliced {
+    reader: Option<fs::File>, // set while no splice out of the pipe is in flight
+    writer: Option<fs::File>, // set while no splice into the pipe is in flight
+    capacity: usize,          // the most that's spliced in at once
+    pending: usize,           // bytes sitting in the pipe
+    inflight: usize,          // tasks left in the chain
+    broken: bool,             // the rest of the chain is cancelled
+    error: Option<io::Error>, // what broke the chain, if it wasn't a short splice
+    eof: bool,                // the splice in hit EOF
+}
+
+impl Spliced {
+    fn new() -> anyhow::Result<Self> {
+        let (reader, writer) = pipe::new()?;
+        let capacity = pipe::capacity(&writer)?;
+
+        Ok(Self {
+            reader: Some(reader),
+            writer: Some(writer),
+            capacity,
+            pending: 0,
+            inflight: 0,
+            broken: false,
+            error: None,
+            eof: false,
+        })
+    }
+
+    // Record a failed task, ignoring those cancelled because an earlier task broke the chain.
+    // NOTE: The kernel completes the task that broke the chain before cancelling the rest.
+    fn fail(&mut self, err: io::Error) {
+        if self.broken && err.raw_os_error() == Some(libc::ECANCELED) {
+            return;
+        }
+
+        self.broken = true;
+        self.error.get_or_insert(err);
+    }
+}
+
+// State for connecting to a backend, which may take multiple attempts.
+struct Dial {
+    client: net::IpAddr,
+    tried: Vec<BackendId>, // backends that failed, avoided if possible
+    deadline: Option<time::Instant>,
+}
+
+impl Dial {
+    // The most to wait between attempts, however many there were.
+    const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+    fn new(client: net::IpAddr, retry: &config::Retry) -> Self {
+        Self {
+            client,
+            tried: Vec::new(),
+            deadline: retry
+                .deadline()
+                .map(|deadline| time::Instant::now() + deadline),
+        }
+    }
+
+    // Time left before we give up, or None if there's no deadline.
+    fn remaining(&self) -> Option<time::Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(time::Instant::now()))
+    }
+
+    // Returns how long to wait before the next attempt, or None if we should give up.
+    fn backoff(&self, retry: &config::Retry) -> Option<time::Duration> {
+        let attempts = self.tried.len() as u32;
+        if attempts >= retry.attempts {
+            return None;
+        }
+
+        // Double the backoff after each failure.
+        let backoff = 2u32
+            .checked_pow(attempts - 1)
+            .and_then(|factor| retry.backoff().checked_mul(factor))
+            .map_or(Self::MAX_BACKOFF, |backoff| backoff.min(Self::MAX_BACKOFF));
+
+        match self.remaining() {
+            Some(remaining) if remaining <= backoff => None,
+            _ => Some(backoff),
+        }
+    }
+}
+
+// A proxied connection, made up of a pipe in each direction.
+// The connection lives until both pipes are done, either side hits an error, or no data has moved
+// in either direction for the idle timeout.
+struct Connection {
+    incoming: Pipe,
+    outgoing: Pipe,
+
+    // Used to reset the sockets on error.
+    // NOTE: The sockets stay open while the connection exists, since each one always has a
+    // half that's owned by a pipe or by an in-flight task.
+    frontend: RawFd,
+    backend: Option<RawFd>,
+
+    backend_id: Option<BackendId>, // released when the connection is closed
+    dial: Option<Dial>,            // set until the backend is connected
+    closing: bool,                 // close once the in-flight write finishes
+    tasks: Vec<TaskId>,            // in-flight tasks, cancelled on close
+    active: time::Instant,         // when data last moved in either direction
+    timer: Option<TimerId>,        // checks for idleness once the backend is connected
+}
+
+impl Connection {
+    fn pipe(&mut self, direction: Direction) -> &mut Pipe {
+        match direction {
+            Direction::Incoming => &mut self.incoming,
+            Direction::Outgoing => &mut self.outgoing,
+        }
+    }
+
+    // Data moved, so the connection isn't idle.
+    fn touch(&mut self) {
+        self.active = time::Instant::now();
+    }
+}
+
+pub struct Connections {
+    conns: Slab<Connection>,
+    tasks: HashMap<TaskId, (usize, Direction)>, // TODO replace with some form of vector
+    timers: HashMap<TimerId, usize>,            // idle checks of connections
+    starved: VecDeque<(usize, Direction)>,      // pipes waiting for a free buffer
+    paused: u64,                                // reads paused because no buffer was free
+    mode: config::BufferMode,                   // may differ from the config if unsupported
+    zerocopy: usize,                            // the smallest write sent without copying, or 0
+}
+
+impl Connections {
+    pub fn new(mode: config::BufferMode, zerocopy: usize) -> Self {
+        Self {
+            conns: Slab::new(),
+            tasks: HashMap::new(),
+            timers: HashMap::new(),
+            starved: VecDeque::new(),
+            paused: 0,
+            mode,
+            zerocopy,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    // The number of times a read waited for a buffer to be given back.
+    pub fn paused(&self) -> u64 {
+        self.paused
+    }
+
+    // Close every connection without waiting for them to finish.
+    pub fn close_all(&mut self, kio: &mut Kio, pool: &mut backend::Pool, config: &Config) {
+        let ids: Vec<usize> = self.conns.iter().map(|(id, _)| id).collect();
+        for conn_id in ids {
+            self.close(kio, pool, config, conn_id, false);
+        }
+    }
+
+    // Start proxying a new client connection, beginning with connecting to a backend.
+    pub fn accept(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        frontend: net::TcpStream,
+        client: net::SocketAddr,
+    ) {
+        let fd = frontend.as_raw_fd();
+        let (frontend_reader, frontend_writer) = kio.register(frontend);
+
+        let conn_id = self.conns.insert(Connection {
+            // The frontend is read once the backend is connected.
+            incoming: Pipe {
+                reader: Some(frontend_reader),
+                ..Pipe::default()
+            },
+            outgoing: Pipe {
+                writer: Some(frontend_writer),
+                ..Pipe::default()
+            },
+            frontend: fd,
+            backend: None,
+            backend_id: None,
+            dial: Some(Dial::new(client.ip(), &config.retry)),
+            closing: false,
+            tasks: Vec::new(),
+            active: time::Instant::now(),
+            timer: None,
+        });
+
+        if !self.dial(kio, pool, config, conn_id) {
+            self.fail(kio, pool, config, conn_id);
+        }
+    }
+
+    pub fn connected(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        connect: completion::Connect,
+    ) {
+        let (conn_id, _) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        let conn = &mut self.conns[conn_id];
+
+        // Failed connections count against the backend's health too, but only while health checks
+        // are running, since nothing else would mark it back up.
+        let backend_id = conn.backend_id.unwrap();
+        if config.health.interval().is_some() {
+            pool.report(backend_id, connect.result.is_ok());
+        }
+
+        if let Err(err) = connect.result {
+            println!("failed to connect to backend: {:?}", err);
+
+            conn.backend_id = None;
+            conn.backend = None;
+            pool.release(backend_id);
+
+            // Try again after a backoff, most likely with a different backend.
+            match conn.dial.as_ref().unwrap().backoff(&config.retry) {
+                Some(backoff) => {
+                    let id = kio.sleep(backoff);
+                    self.track(id, conn_id, Direction::Outgoing);
+                }
+                None => self.fail(kio, pool, config, conn_id),
+            }
+
+            return;
+        }
+
+        conn.dial = None;
+        conn.touch();
+
+        // Both directions share a single idle check, so a one-way transfer keeps it open.
+        if let Some(idle) = config.timeout.idle() {
+            self.idle(kio, conn_id, idle);
+        }
+
+        let conn = &mut self.conns[conn_id];
+
+        // Start reading from both sides now that there's somewhere to write.
+        let frontend_reader = conn.incoming.reader.take().unwrap();
+
+        let (incoming, outgoing) = match self.mode {
+            config::BufferMode::Slice => (
+                Some(buffer::Slice::new(1024)),
+                Some(buffer::Slice::new(4096)),
+            ),
+            config::BufferMode::Splice => {
+                conn.incoming.reader = Some(frontend_reader);
+                conn.outgoing.reader = Some(connect.task.socket);
+
+                return self.spliced_both(kio, pool, config, conn_id);
+            }
+            _ => (None, None),
+        };
+
+        self.read(
+            kio,
+            config,
+            conn_id,
+            Direction::Incoming,
+            frontend_reader,
+            incoming,
+        );
+        self.read(
+            kio,
+            config,
+            conn_id,
+            Direction::Outgoing,
+            connect.task.socket,
+            outgoing,
+        );
+    }
+
+    // The backoff before retrying the connection finished.
+    pub fn slept(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+    ) {
+        let (conn_id, _) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        if !self.dial(kio, pool, config, conn_id) {
+            self.fail(kio, pool, config, conn_id);
+        }
+    }
+
+    // An idle check of a connection is due.
+    pub fn expired(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        timer: TimerId,
+    ) {
+        let conn_id = match self.timers.remove(&timer) {
+            Some(conn_id) => conn_id,
+            None => return,
+        };
+
+        self.idled(kio, pool, config, conn_id);
+    }
+
+    // The read finished, so write the data to the other side.
+    #[allow(clippy::too_many_arguments)]
+    pub fn received(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        socket: tcp::Reader,
+        buffer: Option<Buffer>, // None if the kernel didn't pick a provided buffer
+        size: io::Result<usize>,
+    ) {
+        let (conn_id, direction) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return self.release(kio, config, buffer),
+        };
+
+        let size = match size {
+            Ok(size) => size,
+            Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                // Out of provided buffers, so wait until one is given back.
+                // One may have been given back since, which wouldn't resume this read.
+                if kio.provided() > 0 {
+                    return self.read(kio, config, conn_id, direction, socket, None);
+                }
+
+                return self.starve(conn_id, direction, socket);
+            }
+            Err(err) => {
+                // Cancelled reads are closed gracefully.
+                let abort = err.raw_os_error() != Some(libc::ECANCELED);
+                if abort {
+                    println!("failed to read: {}", err);
+                }
+
+                self.release(kio, config, buffer);
+                self.close(kio, pool, config, conn_id, abort);
+                return;
+            }
+        };
+
+        if size == 0 {
+            self.release(kio, config, buffer);
+            return self.shutdown(kio, pool, config, conn_id, direction);
+        }
+
+        let buffer = buffer.expect("read data without a buffer");
+
+        let conn = &mut self.conns[conn_id];
+        conn.touch();
+
+        let pipe = conn.pipe(direction);
+        let writer = pipe.writer.take().unwrap();
+
+        // Read again once the data has been written.
+        pipe.reader.replace(socket);
+
+        let write = self.write(writer, buffer, 0..size);
+        let id = kio.run_timeout(write, config.timeout.write()).detach();
+        self.track(id, conn_id, direction);
+    }
+
+    // A multishot read received a chunk, so write it to the other side or queue it.
+    // The reader is only returned once the reads stop, after EOF, an error or running out of buffers.
+    #[allow(clippy::too_many_arguments)]
+    pub fn received_multi(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        socket: Option<tcp::Reader>,
+        buffer: Option<buffer::Fixed>,
+        size: io::Result<usize>,
+    ) {
+        let task = match socket {
+            Some(_) => self.finish(task_id),
+            None => self.tasks.get(&task_id).copied(), // still armed
+        };
+
+        let (conn_id, direction) = match task {
+            Some(task) => task,
+            None => return self.release(kio, config, buffer.map(Buffer::Fixed)),
+        };
+
+        let starved = match size {
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOBUFS) => kio.provided() == 0,
+            Err(ref err) if err.raw_os_error() == Some(libc::ECANCELED) => false, // queue was full
+            Err(err) => {
+                println!("failed to read: {}", err);
+
+                self.release(kio, config, buffer.map(Buffer::Fixed));
+                return self.close(kio, pool, config, conn_id, true);
+            }
+            Ok(_) => false,
+        };
+
+        let conn = &mut self.conns[conn_id];
+        if let Ok(1..) = size {
+            conn.touch();
+        }
+
+        let pipe = conn.pipe(direction);
+        let recv = pipe.recv.as_mut().unwrap();
+
+        match size {
+            Ok(0) => recv.eof = true,
+            Ok(size) => {
+                let buffer = buffer.expect("read data without a buffer");
+                recv.queue.push_back((buffer, size));
+
+                // Too far ahead of the writer, so stop reading until the queue is written.
+                if recv.queue.len() >= QUEUED {
+                    if let Some(id) = recv.id.take() {
+                        kio.cancel(id);
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+
+        if let Some(socket) = socket {
+            recv.id = None;
+
+            if starved {
+                self.starve(conn_id, direction, socket);
+            } else if !recv.eof {
+                // Read again once the queue has been written.
+                pipe.reader = Some(socket);
+            }
+        }
+
+        self.forward(kio, pool, config, conn_id, direction);
+    }
+
+    // Write the next chunk that a multishot read queued, if the writer is free.
+    // Reading resumes once the queue has been written, and EOF is passed along after that.
+    fn forward(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+    ) {
+        let pipe = self.conns[conn_id].pipe(direction);
+        let recv = pipe.recv.as_mut().unwrap();
+
+        if pipe.writer.is_some() {
+            if let Some((buffer, size)) = recv.queue.pop_front() {
+                let writer = pipe.writer.take().unwrap();
+
+                let write = self.write(writer, Buffer::Fixed(buffer), 0..size);
+                let id = kio.run_timeout(write, config.timeout.write()).detach();
+                return self.track(id, conn_id, direction);
+            }
+
+            if recv.eof {
+                return self.shutdown(kio, pool, config, conn_id, direction);
+            }
+        }
+
+        if recv.queue.is_empty() && !pipe.starved {
+            if let Some(reader) = pipe.reader.take() {
+                self.read(kio, config, conn_id, direction, reader, None);
+            }
+        }
+    }
+
+    // Close the connection if no data has moved in either direction in time, or check again later.
+    // Rather than resetting a timer for every chunk, the check is pushed back by the time since the
+    // last one.
+    fn idled(&mut self, kio: &mut Kio, pool: &mut backend::Pool, config: &Config, conn_id: usize) {
+        let idle = match config.timeout.idle() {
+            Some(idle) => idle,
+            None => return,
+        };
+
+        let conn = &mut self.conns[conn_id];
+        conn.timer = None;
+
+        // Reads waiting for a buffer are waiting on us, not the socket.
+        let starved = conn.incoming.starved || conn.outgoing.starved;
+
+        let wait = match idle.checked_sub(conn.active.elapsed()) {
+            _ if starved => idle,
+            Some(wait) if !wait.is_zero() => wait,
+            _ => return self.close(kio, pool, config, conn_id, false),
+        };
+
+        self.idle(kio, conn_id, wait);
+    }
+
+    // Check the connection for idleness once the duration has passed.
+    fn idle(&mut self, kio: &mut Kio, conn_id: usize, wait: time::Duration) {
+        let timer = kio.timer(time::Instant::now() + wait);
+        self.timers.insert(timer, conn_id);
+        self.conns[conn_id].timer = Some(timer);
+    }
+
+    // The write finished, so continue writing or read the next data.
+    #[allow(clippy::too_many_arguments)]
+    pub fn written(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        socket: tcp::Writer,
+        buffer: Buffer,
+        range: ops::Range<usize>,
+        size: io::Result<usize>,
+    ) {
+        let (conn_id, direction) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return self.release(kio, config, Some(buffer)),
+        };
+
+        let size = match size {
+            Ok(size) => size,
+            Err(err) => {
+                let abort = err.raw_os_error() != Some(libc::ECANCELED);
+                if abort {
+                    println!("failed to write: {}", err);
+                }
+
+                self.release(kio, config, Some(buffer));
+                self.close(kio, pool, config, conn_id, abort);
+                return;
+            }
+        };
+
+        let conn = &mut self.conns[conn_id];
+        if size > 0 {
+            conn.touch();
+        }
+
+        if size < range.len() {
+            // Continue writing the rest of data.
+            let write = self.write(socket, buffer, range.start + size..range.end);
+            let id = kio.run_timeout(write, config.timeout.write()).detach();
+            self.track(id, conn_id, direction);
+
+            return;
+        }
+
+        if conn.closing {
+            self.release(kio, config, Some(buffer));
+            return self.close(kio, pool, config, conn_id, false);
+        }
+
+        let pipe = conn.pipe(direction);
+        pipe.writer.replace(socket);
+
+        if pipe.recv.is_some() {
+            self.release(kio, config, Some(buffer));
+            return self.forward(kio, pool, config, conn_id, direction);
+        }
+
+        let reader = pipe.reader.take().unwrap();
+
+        match buffer {
+            Buffer::Slice(slice) => self.read(kio, config, conn_id, direction, reader, Some(slice)),
+            Buffer::Fixed(_) => {
+                // Give the buffer back first, so pipes that were waiting get a turn.
+                self.release(kio, config, Some(buffer));
+                self.read(kio, config, conn_id, direction, reader, None);
+            }
+        }
+    }
+
+    // A zero-copy send finished, so read the next data.
+    // The buffer is still held by the kernel, and is given back once notified.
+    #[allow(clippy::too_many_arguments)]
+    pub fn sent(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        socket: tcp::Writer,
+        size: io::Result<usize>,
+        len: usize,
+    ) {
+        let (conn_id, direction) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        // The buffer can't be sent from again until we're notified, so a short send is an error.
+        let size = match size {
+            Ok(size) if size < len => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "short zero-copy send",
+            )),
+            size => size,
+        };
+
+        if let Err(err) = size {
+            let abort = err.raw_os_error() != Some(libc::ECANCELED);
+            if abort {
+                println!("failed to send: {}", err);
+            }
+
+            return self.close(kio, pool, config, conn_id, abort);
+        }
+
+        let conn = &mut self.conns[conn_id];
+        conn.touch();
+
+        if conn.closing {
+            return self.close(kio, pool, config, conn_id, false);
+        }
+
+        let pipe = conn.pipe(direction);
+        pipe.writer.replace(socket);
+
+        if pipe.recv.is_some() {
+            return self.forward(kio, pool, config, conn_id, direction);
+        }
+
+        let reader = pipe.reader.take().unwrap();
+        self.read(kio, config, conn_id, direction, reader, None);
+    }
+
+    // The kernel is done with a zero-copy send's buffer, so give it back.
+    pub fn notified(&mut self, kio: &mut Kio, config: &Config, buffer: Buffer) {
+        self.release(kio, config, Some(buffer));
+    }
+
+    // A poll in the splice chain finished.
+    pub fn polled(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        events: io::Result<libc::c_short>,
+    ) {
+        let (conn_id, direction) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        if let Err(err) = events {
+            let pipe = self.conns[conn_id].pipe(direction);
+            pipe.spliced.as_mut().unwrap().fail(err);
+        }
+
+        self.chained(kio, pool, config, conn_id, direction);
+    }
+
+    // A splice into or out of the kernel pipe finished.
+    pub fn spliced(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        task_id: TaskId,
+        task: task::Splice,
+        size: io::Result<usize>,
+    ) {
+        let (conn_id, direction) = match self.finish(task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        // The sockets are non-blocking for splices, so EAGAIN means nothing was moved.
+        let size = match size {
+            Err(err) if err.raw_os_error() == Some(libc::EAGAIN) => Ok(None),
+            size => size.map(Some),
+        };
+
+        let conn = &mut self.conns[conn_id];
+        if let Ok(Some(1..)) = size {
+            conn.touch();
+        }
+
+        let pipe = conn.pipe(direction);
+        let spliced = pipe.spliced.as_mut().unwrap();
+
+        match task {
+            task::Splice::In {
+                socket, pipe: end, ..
+            } => {
+                pipe.reader = Some(socket);
+                spliced.writer = Some(end);
+
+                match size {
+                    Ok(Some(size)) => {
+                        spliced.pending += size;
+                        spliced.eof = size == 0;
+                    }
+                    Ok(None) => {}
+                    Err(err) => spliced.fail(err),
+                }
+            }
+            task::Splice::Out {
+                pipe: end, socket, ..
+            } => {
+                pipe.writer = Some(socket);
+                spliced.reader = Some(end);
+
+                match size {
+                    Ok(size) => spliced.pending -= size.unwrap_or(0),
+                    Err(err) => spliced.fail(err),
+                }
+            }
+        }
+
+        self.chained(kio, pool, config, conn_id, direction);
+    }
+
+    // A task in the splice chain finished, so splice the next chunk once they all have.
+    fn chained(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+    ) {
+        let spliced = self.conns[conn_id]
+            .pipe(direction)
+            .spliced
+            .as_mut()
+            .unwrap();
+
+        spliced.inflight -= 1;
+        if spliced.inflight > 0 {
+            return;
+        }
+
+        if let Some(err) = spliced.error.take() {
+            // Not every socket can be spliced, so copy instead of giving up on the connection.
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                return self.unspliced(kio, pool, config, conn_id, direction);
+            }
+
+            // Timeouts cancel the chain, and are closed gracefully.
+            let abort = err.raw_os_error() != Some(libc::ECANCELED);
+            if abort {
+                println!("failed to splice: {}", err);
+            }
+
+            return self.close(kio, pool, config, conn_id, abort);
+        }
+
+        if spliced.eof && spliced.pending == 0 {
+            return self.shutdown(kio, pool, config, conn_id, direction);
+        }
+
+        self.splice(kio, config, conn_id, direction);
+    }
+
+    // Create a kernel pipe for each direction and start splicing through them.
+    fn spliced_both(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+    ) {
+        for direction in &[Direction::Incoming, Direction::Outgoing] {
+            let spliced = match Spliced::new() {
+                Ok(spliced) => spliced,
+                Err(err) => {
+                    println!("failed to create pipe: {}", err);
+                    return self.close(kio, pool, config, conn_id, false);
+                }
+            };
+
+            self.conns[conn_id].pipe(*direction).spliced = Some(spliced);
+        }
+
+        self.splice(kio, config, conn_id, Direction::Incoming);
+        self.splice(kio, config, conn_id, Direction::Outgoing);
+    }
+
+    // Stop splicing in the direction and copy its data through a slice instead, starting with what's
+    // left in the pipe.
+    fn unspliced(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+    ) {
+        println!("can't splice, copying instead");
+
+        let pipe = self.conns[conn_id].pipe(direction);
+        let mut spliced = pipe.spliced.take().unwrap();
+        let pending = spliced.pending;
+
+        let mut buffer = buffer::Slice::new(cmp::max(config.buffers.size, pending));
+
+        // The data is already in the pipe, so this doesn't block.
+        let mut reader = spliced.reader.take().unwrap();
+        if let Err(err) = reader.read_exact(&mut buffer[..pending]) {
+            println!("failed to empty pipe: {}", err);
+            return self.close(kio, pool, config, conn_id, true);
+        }
+
+        if pending == 0 {
+            if spliced.eof {
+                return self.shutdown(kio, pool, config, conn_id, direction);
+            }
+
+            let reader = pipe.reader.take().unwrap();
+            return self.read(kio, config, conn_id, direction, reader, Some(buffer));
+        }
+
+        // The reader is picked up once the write finishes, as if it had just read the data.
+        let writer = pipe.writer.take().unwrap();
+        let write = task::Write::new(writer, buffer, 0..pending);
+        let id = kio
+            .run_timeout(write.into(), config.timeout.write())
+            .detach();
+        self.track(id, conn_id, direction);
+    }
+
+    // Splice the next chunk of data from the reader into the pipe, linked to splicing it out to the
+    // writer so the data is passed along without waiting on us.
+    // Anything left in the pipe from a short splice out is spliced out first.
+    fn splice(&mut self, kio: &mut Kio, config: &Config, conn_id: usize, direction: Direction) {
+        let pipe = self.conns[conn_id].pipe(direction);
+        let spliced = pipe.spliced.as_mut().unwrap();
+
+        spliced.broken = false;
+
+        let mut chain = kio.chain();
+
+        let len = if spliced.pending > 0 {
+            spliced.pending
+        } else {
+            let socket = pipe.reader.take().unwrap();
+            let poll = task::Poll {
+                target: socket.target(),
+                events: libc::POLLIN,
+            };
+
+            let splice_in = task::Splice::In {
+                socket,
+                pipe: spliced.writer.take().unwrap(),
+                len: spliced.capacity as u32,
+            };
+
+            // However much the splice in moves, the splice out takes it from the pipe.
+            chain = chain.then(poll.into()).partial(splice_in.into());
+
+            spliced.capacity
+        };
+
+        let socket = pipe.writer.take().unwrap();
+        let poll = task::Poll {
+            target: socket.target(),
+            events: libc::POLLOUT,
+        };
+
+        let splice_out = task::Splice::Out {
+            pipe: spliced.reader.take().unwrap(),
+            socket,
+            len: len as u32,
+        };
+
+        chain = chain.then(poll.into());
+        if let Some(write) = config.timeout.write() {
+            chain = chain.timeout(write);
+        }
+        chain = chain.then(splice_out.into());
+
+        let links = chain.submit();
+        spliced.inflight = links.len();
+
+        for id in links.ids() {
+            self.track(id, conn_id, direction);
+        }
+    }
+
+    // Write the range of the buffer to the socket, sending it without copying if it's large enough.
+    // Slices are read into again as soon as they're written, so they're always copied.
+    fn write(
+        &self,
+        socket: tcp::Writer,
+        buffer: Buffer,
+        range: ops::Range<usize>,
+    ) -> task::TaskType {
+        match buffer {
+            Buffer::Fixed(_) if self.zerocopy > 0 && range.len() >= self.zerocopy => {
+                task::SendZc::new(socket, buffer, range).into()
+            }
+            Buffer::Fixed(buffer) => task::WriteFixed::new(socket, buffer, range).into(),
+            Buffer::Slice(buffer) => task::Write::new(socket, buffer, range).into(),
+        }
+    }
+
+    // Read the next chunk of data, into the slice if there is one or a buffer from the pool otherwise.
+    // Reads are paused instead when the pool is empty, until a buffer is given back.
+    fn read(
+        &mut self,
+        kio: &mut Kio,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+        socket: tcp::Reader,
+        slice: Option<buffer::Slice>,
+    ) {
+        let read: task::TaskType = match (slice, self.mode) {
+            (Some(buffer), _) => task::Read { socket, buffer }.into(),
+            (None, config::BufferMode::Fixed) => match kio.buffers().take() {
+                Some(buffer) => task::ReadFixed { socket, buffer }.into(),
+                None => return self.starve(conn_id, direction, socket),
+            },
+            (None, config::BufferMode::Multishot) => {
+                return self.recv(kio, config, conn_id, direction, socket);
+            }
+            (None, _) => task::ReadProvided::new(socket, config.buffers.size).into(),
+        };
+
+        let id = kio.run(read).detach();
+        self.track(id, conn_id, direction);
+    }
+
+    // Arm a multishot read, which completes for each chunk until it's stopped.
+    fn recv(
+        &mut self,
+        kio: &mut Kio,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+        socket: tcp::Reader,
+    ) {
+        let id = kio.recv_multi(socket, config.buffers.size);
+        self.track(id, conn_id, direction);
+
+        let recv = self.conns[conn_id]
+            .pipe(direction)
+            .recv
+            .get_or_insert_with(Recv::default);
+
+        recv.id = Some(id);
+    }
+
+    // Park the reader until a buffer is given back.
+    fn starve(&mut self, conn_id: usize, direction: Direction, socket: tcp::Reader) {
+        let pipe = self.conns[conn_id].pipe(direction);
+        pipe.reader = Some(socket);
+        pipe.starved = true;
+
+        self.starved.push_back((conn_id, direction));
+        self.paused += 1;
+    }
+
+    // Done with the buffer, so give it back to the kernel or the pool.
+    // That frees up a buffer for a read that ran out.
+    fn release(&mut self, kio: &mut Kio, config: &Config, buffer: Option<Buffer>) {
+        let buffer = match buffer {
+            Some(Buffer::Fixed(buffer)) => buffer,
+            _ => return,
+        };
+
+        let all = match self.mode {
+            config::BufferMode::Provided | config::BufferMode::Multishot => {
+                kio.provide(buffer);
+                true
+            }
+            _ => {
+                kio.buffers().give(buffer);
+                false
+            }
+        };
+
+        // A provided buffer is only picked once data arrives, so resume every waiting read in case
+        // the first is idle. A buffer from the pool is taken right away, so only the first gets it.
+        let mut waiting = mem::take(&mut self.starved).into_iter();
+
+        for (conn_id, direction) in &mut waiting {
+            // Skip connections that closed in the meantime.
+            let pipe = match self.conns.get_mut(conn_id) {
+                Some(conn) => conn.pipe(direction),
+                None => continue,
+            };
+
+            if !pipe.starved {
+                continue;
+            }
+
+            pipe.starved = false;
+
+            let reader = pipe.reader.take().unwrap();
+            self.read(kio, config, conn_id, direction, reader, None);
+
+            if !all {
+                break;
+            }
+        }
+
+        // Keep the rest waiting in order.
+        let mut starved: VecDeque<_> = waiting.collect();
+        starved.append(&mut self.starved);
+        self.starved = starved;
+    }
+
+    // Connect to the next backend, returning false if there are none left to try.
+    fn dial(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+    ) -> bool {
+        let conn = &mut self.conns[conn_id];
+        let dial = conn.dial.as_mut().unwrap();
+
+        let timeout = match (config.timeout.connect(), dial.remaining()) {
+            (_, Some(remaining)) if remaining == time::Duration::from_secs(0) => return false,
+            (Some(timeout), Some(remaining)) => Some(cmp::min(timeout, remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+
+        let (backend_id, backend_addr) = match pool.acquire(&dial.client, &dial.tried) {
+            Some(backend) => backend,
+            None => {
+                println!("no backends available");
+                return false;
+            }
+        };
+
+        dial.tried.push(backend_id);
+
+        // Create a TCP socket matching the backend's address family.
+        let backend = match tcp::socket(&backend_addr) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("failed to create socket: {}", err);
+                pool.release(backend_id);
+                return false;
+            }
+        };
+
+        conn.backend = Some(backend.as_raw_fd());
+        conn.backend_id = Some(backend_id);
+
+        let (backend_reader, backend_writer) = kio.register(backend);
+        conn.incoming.writer = Some(backend_writer);
+
+        let connect = task::Connect::new(backend_reader, backend_addr);
+        let id = kio.run_timeout(connect.into(), timeout).detach();
+        self.track(id, conn_id, Direction::Outgoing);
+
+        true
+    }
+
+    // Give up on connecting to a backend.
+    // HTTP clients are told with a 502, otherwise the connection is just closed.
+    fn fail(&mut self, kio: &mut Kio, pool: &mut backend::Pool, config: &Config, conn_id: usize) {
+        const BAD_GATEWAY: &[u8] =
+            b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+        if config.mode != config::Mode::Http {
+            return self.close(kio, pool, config, conn_id, false);
+        }
+
+        let conn = &mut self.conns[conn_id];
+        conn.closing = true;
+
+        let mut buffer = buffer::Slice::new(BAD_GATEWAY.len());
+        buffer.copy_from_slice(BAD_GATEWAY);
+
+        let writer = conn.outgoing.writer.take().unwrap();
+
+        let write = task::Write::new(writer, buffer, ..);
+        let id = kio
+            .run_timeout(write.into(), config.timeout.write())
+            .detach();
+        self.track(id, conn_id, Direction::Outgoing);
+    }
+
+    // The reader hit EOF, so shut down the writer to pass it along.
+    // The connection is closed once both directions are done.
+    fn shutdown(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+        direction: Direction,
+    ) {
+        let conn = &mut self.conns[conn_id];
+
+        let pipe = conn.pipe(direction);
+        pipe.reader = None;
+        pipe.writer = None; // shutdown(Write) on drop
+        pipe.spliced = None;
+        pipe.recv = None;
+        pipe.done = true;
+
+        if conn.incoming.done && conn.outgoing.done {
+            self.close(kio, pool, config, conn_id, false);
+        }
+    }
+
+    // Close both directions of the connection, cancelling any in-flight tasks.
+    // The sockets and buffers are released once the cancelled tasks complete.
+    // If abort is set, both sockets are reset instead of closed gracefully.
+    fn close(
+        &mut self,
+        kio: &mut Kio,
+        pool: &mut backend::Pool,
+        config: &Config,
+        conn_id: usize,
+        abort: bool,
+    ) {
+        let conn = self.conns.remove(conn_id);
+
+        if abort {
+            for fd in Some(conn.frontend).iter().chain(conn.backend.iter()) {
+                let _ = tcp::abort(*fd);
+            }
+        }
+
+        if let Some(backend_id) = conn.backend_id {
+            pool.release(backend_id);
+        }
+
+        for task_id in conn.tasks {
+            self.tasks.remove(&task_id);
+            kio.cancel(task_id);
+        }
+
+        if let Some(timer) = conn.timer {
+            self.timers.remove(&timer);
+            kio.cancel_timer(timer);
+        }
+
+        // No task holds the chunks that multishot reads queued, so give them back now.
+        let queued = conn.incoming.recv.into_iter().chain(conn.outgoing.recv);
+        for (buffer, _) in queued.flat_map(|recv| recv.queue) {
+            self.release(kio, config, Some(Buffer::Fixed(buffer)));
+        }
+    }
+
+    // Associate an in-flight task with the connection.
+    fn track(&mut self, task_id: TaskId, conn_id: usize, direction: Direction) {
+        self.conns[conn_id].tasks.push(task_id);
+        self.tasks.insert(task_id, (conn_id, direction));
+    }
+
+    // Returns the connection that the finished task belonged to, if it's still open.
+    fn finish(&mut self, task_id: TaskId) -> Option<(usize, Direction)> {
+        let (conn_id, direction) = self.tasks.remove(&task_id)?;
+
+        let conn = self.conns.get_mut(conn_id)?;
+        conn.tasks.retain(|id| *id != task_id);
+
+        Some((conn_id, direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoffs(retry: &config::Retry) -> Vec<Option<time::Duration>> {
+        let mut dial = Dial::new(net::Ipv4Addr::LOCALHOST.into(), retry);
+
+        (0..retry.attempts)
+            .map(|id| {
+                dial.tried.push(id as BackendId);
+                dial.backoff(retry)
+            })
+            .collect()
+    }
+
+    fn millis(ms: u64) -> Option<time::Duration> {
+        Some(time::Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let retry = config::Retry {
+            attempts: 4,
+            backoff_ms: 50,
+            deadline_ms: 0,
+        };
+
+        assert_eq!(
+            backoffs(&retry),
+            vec![millis(50), millis(100), millis(200), None]
+        );
+    }
+
+    #[test]
+    fn backoff_deadline() {
+        let retry = config::Retry {
+            attempts: 10,
+            backoff_ms: 50,
+            deadline_ms: 120,
+        };
+
+        // Gives up rather than waiting past the deadline.
+        assert_eq!(backoffs(&retry)[..3], [millis(50), millis(100), None]);
+    }
+
+    #[test]
+    fn backoff_limit() {
+        let retry = config::Retry {
+            attempts: 40,
+            backoff_ms: 1 << 40,
+            deadline_ms: 0,
+        };
+
+        let backoffs = backoffs(&retry);
+        assert_eq!(backoffs[0], Some(Dial::MAX_BACKOFF));
+        assert_eq!(backoffs[38], Some(Dial::MAX_BACKOFF));
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{env, fs, io, mem, net, path, process, ptr, time};
+
+use anyhow::Context;
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::sys::socket::{self, sockopt, ControlMessage, MsgFlags};
+use nix::sys::uio::IoVec;
+
+// The most descriptors the kernel passes in one message, so larger handoffs are sent in batches.
+const SCM_MAX_FD: usize = 253;
+
+// How long either side waits on the other during the handoff.
+const HANDOFF_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// Systemd passes sockets starting after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+// Take over the listeners from systemd socket activation or from the old process, if any.
+pub fn inherit(path: Option<&path::Path>) -> anyhow::Result<Vec<net::TcpListener>> {
+    let listeners = systemd()?;
+    if !listeners.is_empty() {
+        return Ok(listeners);
+    }
+
+    match path {
+        Some(path) => receive(path),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Listen for a new process that wants to take over.
+pub fn listen(path: &path::Path) -> anyhow::Result<UnixListener> {
+    // Replace the socket left behind by the old process.
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind handoff socket: {}", path.display()))?;
+
+    Ok(listener)
+}
+
+// Send the listeners to the new process, which starts accepting on them immediately.
+// They're preceded by their count and sent in batches, and the new process acknowledges once it
+// has them all, so the old one only stops if the handoff succeeded.
+pub fn send(stream: &UnixStream, listeners: &[RawFd]) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let mut stream = stream;
+    stream.write_all(&(listeners.len() as u32).to_ne_bytes())?;
+
+    for batch in listeners.chunks(SCM_MAX_FD) {
+        // At least one byte of data must accompany the descriptors.
+        let iov = [IoVec::from_slice(b"w")];
+        let cmsgs = [ControlMessage::ScmRights(batch)];
+
+        socket::sendmsg(stream.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+    }
+
+    let mut ack = [0u8; 1];
+    stream
+        .read_exact(&mut ack)
+        .context("new process didn't take over")?;
+
+    Ok(())
+}
+
+// Adopt the sockets passed via LISTEN_FDS, as described in sd_listen_fds(3).
+fn systemd() -> anyhow::Result<Vec<net::TcpListener>> {
+    let pid = env::var("LISTEN_PID");
+    let count = env::var("LISTEN_FDS");
+
+    // Don't pass the variables on to any children.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (pid, count) = match (pid, count) {
+        (Ok(pid), Ok(count)) => (pid, count),
+        _ => return Ok(Vec::new()),
+    };
+
+    // The sockets were meant for another process.
+    if pid.parse::<u32>().context("invalid LISTEN_PID")? != process::id() {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = count.parse().context("invalid LISTEN_FDS")?;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(adopt)
+        .collect()
+}
+
+// Ask the old process for its listeners, if there's one listening on the handoff socket.
+fn receive(path: &path::Path) -> anyhow::Result<Vec<net::TcpListener>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(Vec::new())
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to connect: {}", path.display()))
+        }
+    };
+
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let mut stream = &stream;
+
+    let mut count = [0u8; 4];
+    stream
+        .read_exact(&mut count)
+        .context("failed to receive listener count")?;
+    let count = u32::from_ne_bytes(count) as usize;
+
+    // Take ownership first so nothing leaks on error.
+    let mut listeners = Vec::with_capacity(count);
+
+    while listeners.len() < count {
+        let batch = receive_batch(stream, (count - listeners.len()).min(SCM_MAX_FD))?;
+        if batch.is_empty() {
+            anyhow::bail!("old process stopped handing off listeners");
+        }
+
+        listeners.extend(batch);
+    }
+
+    for listener in &listeners {
+        check(listener.as_raw_fd())?;
+    }
+
+    // Let the old process stop now that it's been taken over.
+    stream
+        .write_all(b"w")
+        .context("failed to acknowledge handoff")?;
+
+    Ok(listeners)
+}
+
+// Receive one message of up to size descriptors.
+fn receive_batch(stream: &UnixStream, size: usize) -> anyhow::Result<Vec<net::TcpListener>> {
+    // NOTE: nix's recvmsg crashes while parsing Unix socket addresses, so use libc directly.
+    let mut buf = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as _,
+        iov_len: buf.len(),
+    };
+
+    let space = unsafe { libc::CMSG_SPACE((size * mem::size_of::<RawFd>()) as _) };
+    let mut cmsgs = vec![0u8; space as usize];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsgs.as_mut_ptr() as _;
+    msg.msg_controllen = cmsgs.len() as _;
+
+    let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("failed to receive listeners");
+    }
+
+    let mut listeners = Vec::new();
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while let Some(hdr) = unsafe { cmsg.as_ref() } {
+        if hdr.cmsg_level == libc::SOL_SOCKET && hdr.cmsg_type == libc::SCM_RIGHTS {
+            let data = unsafe { libc::CMSG_DATA(cmsg) } as *const RawFd;
+            let count =
+                (hdr.cmsg_len - unsafe { libc::CMSG_LEN(0) } as usize) / mem::size_of::<RawFd>();
+
+            for i in 0..count {
+                let fd = unsafe { ptr::read_unaligned(data.add(i)) };
+                listeners.push(unsafe { net::TcpListener::from_raw_fd(fd) });
+            }
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        anyhow::bail!("more listeners were sent than expected");
+    }
+
+    Ok(listeners)
+}
+
+fn adopt(fd: RawFd) -> anyhow::Result<net::TcpListener> {
+    let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+
+    fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    check(fd)?;
+
+    Ok(listener)
+}
+
+// Make sure an inherited descriptor is a listening socket.
+fn check(fd: RawFd) -> anyhow::Result<()> {
+    let listening = socket::getsockopt(fd, sockopt::AcceptConn)
+        .with_context(|| format!("inherited fd {} is not a socket", fd))?;
+
+    if !listening {
+        anyhow::bail!("inherited fd {} is not listening", fd);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::{mpsc, Arc};
+use std::{fs, net, path, thread};
+
+use crate::config::{self, Config};
+use crate::kio::completion::CompletionType;
+use crate::kio::setup::Setup;
+use crate::kio::{event, signal, tcp, Kio};
+
+use nix::sys::signal::Signal;
+
+mod connection;
+mod handoff;
+mod worker;
+
+use worker::Worker;
+
+pub struct Proxy {
+    config: Arc<Config>,
+    addrs: Vec<net::SocketAddr>,
+    listeners: Vec<net::TcpListener>, // every listener, kept around to hand off
+    handoff: Option<UnixListener>,
+    workers: Vec<Vec<net::TcpListener>>, // the listeners that each worker accepts on
+}
+
+impl Proxy {
+    // Bind all of the listeners but don't start accepting yet.
+    // Listeners are taken over from systemd or the old process instead when available.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let mut inherited = handoff::inherit(config.handoff.as_deref())?;
+
+        let count = config.workers.count();
+
+        let mut addrs = Vec::new();
+        let mut listeners = Vec::new();
+        let mut workers: Vec<Vec<net::TcpListener>> = (0..count).map(|_| Vec::new()).collect();
+
+        for addr in &config.listen {
+            let (matched, rest): (Vec<_>, Vec<_>) = inherited
+                .into_iter()
+                .partition(|listener| listener.local_addr().ok().as_ref() == Some(addr));
+
+            inherited = rest;
+
+            let sockets = if matched.is_empty() {
+                bind(addr, &config)?
+            } else {
+                matched
+            };
+
+            // Spread the sockets over the workers, sharing them if there are fewer sockets.
+            for (i, socket) in sockets.iter().enumerate() {
+                workers[i % count].push(socket.try_clone()?);
+            }
+
+            for i in sockets.len()..count {
+                workers[i].push(sockets[i % sockets.len()].try_clone()?);
+            }
+
+            addrs.push(sockets[0].local_addr()?);
+            listeners.extend(sockets);
+        }
+
+        for listener in inherited {
+            println!("closing inherited listener {:?}", listener.local_addr());
+        }
+
+        // Bind after taking over, since this replaces the old process's socket.
+        let handoff = config.handoff.as_deref().map(handoff::listen).transpose()?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            addrs,
+            listeners,
+            handoff,
+            workers,
+        })
+    }
+
+    pub fn local_addrs(&self) -> anyhow::Result<Vec<net::SocketAddr>> {
+        Ok(self.addrs.clone())
+    }
+
+    // Run the workers on their own threads, using the current thread to handle signals and handoffs.
+    // SIGTERM and SIGINT stop the workers, and SIGHUP reloads the backends.
+    pub fn run(self) -> anyhow::Result<()> {
+        // Block the signals before spawning so the workers inherit the mask.
+        let signals = signal::listen(&[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP])?;
+
+        // Each worker notifies this once it has finished.
+        let done = event::new()?;
+
+        // Created first so the workers can share its threads.
+        let mut uring = match self.config.ring.share {
+            true => setup(&self.config.ring, None).build()?,
+            false => io_uring::IoUring::new(16)?,
+        };
+
+        let shared = match self.config.ring.share {
+            true => Some(uring.as_raw_fd()),
+            false => None,
+        };
+
+        let mut stops = Vec::new();
+        let mut reloads = Vec::new();
+        let mut threads = Vec::new();
+
+        for (id, listeners) in self.workers.into_iter().enumerate() {
+            let stop = event::new()?;
+            let reload = event::new()?;
+            let (sender, backends) = mpsc::channel();
+
+            let worker = Worker::new(
+                id,
+                self.config.clone(),
+                listeners,
+                stop.try_clone()?,
+                (reload.try_clone()?, backends),
+                shared,
+            );
+            let done = done.try_clone()?;
+
+            let thread = thread::Builder::new()
+                .name(format!("wisp-{}", id))
+                .spawn(move || {
+                    let result = worker.run();
+                    if let Err(err) = &result {
+                        println!("worker {} failed: {}", id, err);
+                    }
+
+                    let _ = event::notify(&done);
+                    result
+                })?;
+
+            stops.push(stop);
+            reloads.push((reload, sender));
+            threads.push(thread);
+        }
+
+        let mut kio = Kio::new(&mut uring)?;
+
+        kio.signal(signals);
+        kio.event(done);
+
+        let mut handoff = self.handoff.map(|handoff| kio.accept_unix(handoff));
+
+        // Dropped when draining, so the listeners close once the workers are done with them.
+        let mut listeners = Some(self.listeners);
+
+        let mut finished = 0;
+
+        while finished < threads.len() {
+            let (_, completion) = kio.wait()?;
+
+            // Set to tell the workers to stop after handling the completion.
+            let mut stop = false;
+
+            match completion {
+                CompletionType::Signal(signal) => {
+                    let fd = signal.task.fd;
+
+                    let signal = match signal.signal {
+                        Ok(signal) => signal,
+                        Err(err) => anyhow::bail!("failed to read signal: {}", err),
+                    };
+
+                    println!("received {}", signal);
+
+                    match signal {
+                        Signal::SIGHUP => reload(self.config.reload.as_deref(), &reloads)?,
+                        // A second signal tells the workers to skip the rest of the drain.
+                        _ => stop = true,
+                    }
+
+                    kio.signal(fd);
+                }
+                CompletionType::AcceptUnix(accept) => {
+                    handoff = None;
+
+                    let stream = match accept.socket {
+                        Ok(stream) => stream,
+                        Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => continue,
+                        Err(err) => {
+                            println!("failed to accept handoff: {}", err);
+                            handoff = Some(kio.accept_unix(accept.task.socket));
+                            continue;
+                        }
+                    };
+
+                    let fds: Vec<RawFd> = match listeners {
+                        Some(ref listeners) => listeners.iter().map(|l| l.as_raw_fd()).collect(),
+                        None => continue,
+                    };
+
+                    // Keep serving if the new process went away before taking over.
+                    if let Err(err) = handoff::send(&stream, &fds) {
+                        println!("failed to hand off listeners: {}", err);
+                        handoff = Some(kio.accept_unix(accept.task.socket));
+                        continue;
+                    }
+
+                    println!("handed off listeners");
+                    stop = true;
+                }
+                CompletionType::Event(event) => {
+                    finished += event.count? as usize;
+                    kio.event(event.task.fd);
+                }
+                CompletionType::Cancel(_) => {}
+                _ => {
+                    panic!("unknown completion")
+                }
+            }
+
+            if stop {
+                for stop in &stops {
+                    event::notify(stop)?;
+                }
+
+                listeners = None;
+
+                if let Some(task_id) = handoff.take() {
+                    kio.cancel(task_id);
+                }
+            }
+        }
+
+        kio.shutdown()?;
+
+        for thread in threads {
+            match thread.join() {
+                Ok(result) => result?,
+                Err(_) => anyhow::bail!("worker panicked"),
+            }
+        }
+
+        println!("shutdown complete");
+
+        Ok(())
+    }
+}
+
+// Read the backends from the config file again and hand them to every worker.
+// The current backends are kept if the file can't be read or is invalid.
+fn reload(
+    path: Option<&path::Path>,
+    workers: &[(fs::File, mpsc::Sender<Vec<config::Backend>>)],
+) -> anyhow::Result<()> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            println!("backends weren't loaded from a config file, so not reloading");
+            return Ok(());
+        }
+    };
+
+    let backends = match config::reload_backends(path) {
+        Ok(backends) => backends,
+        Err(err) => {
+            println!("failed to reload backends: {:#}", err);
+            return Ok(());
+        }
+    };
+
+    println!("reloaded {} backends", backends.len());
+
+    for (reload, sender) in workers {
+        // Workers that already finished are gone.
+        if sender.send(backends.clone()).is_ok() {
+            event::notify(reload)?;
+        }
+    }
+
+    Ok(())
+}
+
+// How to create a ring with the configured options, sharing the threads of another if given.
+fn setup(config: &config::Ring, shared: Option<RawFd>) -> Setup {
+    Setup {
+        entries: config.entries,
+        completions: config.completions,
+        sqpoll: match config.sqpoll {
+            true => Some(config.sqpoll_idle_ms),
+            false => None,
+        },
+        sqpoll_cpu: config.sqpoll_cpu,
+        coop_taskrun: config.coop_taskrun,
+        single_issuer: config.single_issuer,
+        defer_taskrun: config.defer_taskrun,
+        attach: shared,
+    }
+}
+
+// Bind a listener for each worker, sharing the address with SO_REUSEPORT when there are several.
+fn bind(addr: &net::SocketAddr, config: &Config) -> anyhow::Result<Vec<net::TcpListener>> {
+    let count = config.workers.count();
+    if count == 1 {
+        return Ok(vec![tcp::listen(addr, config.v6_only, false)?]);
+    }
+
+    let first = tcp::listen(addr, config.v6_only, true)?;
+
+    // Use the same port for the rest, in case the kernel picked it.
+    let addr = first.local_addr()?;
+
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(tcp::listen(&addr, config.v6_only, true)?);
+    }
+
+    if config.workers.steer {
+        tcp::steer(&listeners[0])?;
+    }
+
+    Ok(listeners)
+}
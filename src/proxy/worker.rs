@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use std::{fs, net};
+
+use io_uring::opcode;
+use nix::sched::{self, CpuSet};
+use nix::unistd::Pid;
+
+use super::connection::Connections;
+use crate::backend;
+use crate::config::{self, Config};
+use crate::kio::buffer::Buffer;
+use crate::kio::completion::CompletionType;
+use crate::kio::task::TaskId;
+use crate::kio::timer::TimerId;
+use crate::kio::Kio;
+
+// How long to wait before accepting again after a listener failed, such as when out of descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Proxies connections on a single thread with its own ring, buffers and backend pool.
+pub struct Worker {
+    id: usize,
+    config: Arc<Config>,
+    listeners: Vec<net::TcpListener>,
+    pool: backend::Pool,
+    shared: Option<RawFd>,
+    stop: fs::File, // eventfd notified once to drain, and again to close immediately
+
+    // An eventfd notified once new backends have been sent.
+    reload: (fs::File, mpsc::Receiver<Vec<config::Backend>>),
+}
+
+impl Worker {
+    pub fn new(
+        id: usize,
+        config: Arc<Config>,
+        listeners: Vec<net::TcpListener>,
+        stop: fs::File,
+        reload: (fs::File, mpsc::Receiver<Vec<config::Backend>>),
+        shared: Option<RawFd>,
+    ) -> Self {
+        let pool = backend::Pool::from_config(&config);
+
+        Self {
+            id,
+            config,
+            listeners,
+            pool,
+            stop,
+            reload,
+            shared,
+        }
+    }
+
+    // Run until told to stop and every connection has finished.
+    pub fn run(self) -> anyhow::Result<()> {
+        let config = self.config;
+        let mut pool = self.pool;
+
+        if config.workers.pin {
+            pin(self.id)?;
+        }
+
+        let mut uring = super::setup(&config.ring, self.shared).build()?;
+        let mut kio = Kio::new(&mut uring)?;
+
+        kio.prepare_buffers(config.buffers.count, config.buffers.size)?;
+
+        if config.ring.files > 0 {
+            if let Err(err) = kio.register_files(config.ring.files) {
+                println!("worker {} can't register files: {}", self.id, err);
+            }
+        }
+
+        let (mode, zerocopy) = path(self.id, &config, &kio);
+        if matches!(
+            mode,
+            config::BufferMode::Provided | config::BufferMode::Multishot
+        ) {
+            // Share the buffers through a ring where possible, otherwise each is provided by a task.
+            if kio.capabilities().buffer_ring {
+                if let Err(err) = kio.register_ring(config.buffers.count) {
+                    println!(
+                        "worker {} providing buffers with tasks, since the ring failed: {}",
+                        self.id, err
+                    );
+                }
+            }
+
+            kio.provide_buffers(config.buffers.count);
+        }
+        kio.event(self.stop);
+
+        let (reload, backends) = self.reload;
+        let reload_fd = reload.as_raw_fd();
+        kio.event(reload);
+
+        let mut accepts: Vec<TaskId> = self
+            .listeners
+            .into_iter()
+            .map(|listener| kio.accept_multi(listener))
+            .collect();
+
+        // Listeners waiting out a failure before accepting again.
+        let mut paused: HashMap<TimerId, net::TcpListener> = HashMap::new();
+
+        let mut checker = backend::Checker::new(&config.health);
+        checker.start(&mut kio, &mut pool);
+
+        let mut conns = Connections::new(mode, zerocopy);
+
+        // Set once told to stop; no new connections are accepted.
+        let mut draining = false;
+        let mut drain_deadline = None;
+
+        'run: while !draining || !conns.is_empty() {
+            // Sleep until something completes or housekeeping is due, then handle everything ready.
+            let deadline = checker.deadline().into_iter().chain(drain_deadline).min();
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            kio.wait_batch(1, timeout)?;
+
+            while let Some((task_id, completion)) = kio.try_wait()? {
+                let completion = match checker.complete(&mut kio, &mut pool, task_id, completion) {
+                    Some(completion) => completion,
+                    None => continue,
+                };
+
+                match completion {
+                    CompletionType::Event(event) if event.task.fd.as_raw_fd() == reload_fd => {
+                        if let Err(err) = event.count {
+                            anyhow::bail!("failed to read reload event: {}", err);
+                        }
+
+                        // Only the latest backends matter if several were sent.
+                        if let Some(backends) = backends.try_iter().last() {
+                            pool.replace(&backends);
+                        }
+
+                        kio.event(event.task.fd);
+                    }
+                    CompletionType::Event(event) => {
+                        let count = match event.count {
+                            Ok(count) => count,
+                            Err(err) => anyhow::bail!("failed to read stop event: {}", err),
+                        };
+
+                        if draining || count > 1 {
+                            // Told to stop again, so skip the rest of the drain.
+                            println!("worker {} closing {} connections", self.id, conns.len());
+                            conns.close_all(&mut kio, &mut pool, &config);
+                            break 'run;
+                        }
+
+                        println!("worker {} draining {} connections", self.id, conns.len());
+                        draining = true;
+
+                        // The listeners are closed when their accepts are cancelled.
+                        for accept in accepts.drain(..) {
+                            kio.cancel(accept);
+                        }
+
+                        for (timer, _) in paused.drain() {
+                            kio.cancel_timer(timer);
+                        }
+
+                        drain_deadline = config.timeout.drain().map(|drain| Instant::now() + drain);
+                        kio.event(event.task.fd);
+                    }
+                    CompletionType::Accept(accept) => {
+                        // The task is only returned once the accepts stop, such as after an error.
+                        if let Some(task) = accept.task {
+                            accepts.retain(|id| *id != task_id);
+
+                            if !draining && accept.socket.is_ok() {
+                                accepts.push(kio.accept_multi(task.socket));
+                            } else if !draining {
+                                // Errors like EMFILE would fail again straight away, so wait first.
+                                let timer = kio.timer(Instant::now() + ACCEPT_BACKOFF);
+                                paused.insert(timer, task.socket);
+                            }
+                        }
+
+                        let frontend = match accept.socket {
+                            Ok(socket) => socket,
+                            Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => continue,
+                            Err(err) => {
+                                println!("failed to accept: {}", err);
+                                continue;
+                            }
+                        };
+
+                        let client = match frontend.peer_addr() {
+                            Ok(addr) => addr,
+                            Err(err) => {
+                                println!("failed to get peer address: {}", err);
+                                continue;
+                            }
+                        };
+
+                        conns.accept(&mut kio, &mut pool, &config, frontend, client);
+                    }
+                    CompletionType::Connect(connect) => {
+                        conns.connected(&mut kio, &mut pool, &config, task_id, connect);
+                    }
+                    CompletionType::Read(read) => {
+                        let task = read.task;
+                        let buffer = Some(Buffer::Slice(task.buffer));
+
+                        conns.received(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            task.socket,
+                            buffer,
+                            read.size,
+                        );
+                    }
+                    CompletionType::ReadFixed(read) => {
+                        let task = read.task;
+                        let buffer = Some(Buffer::Fixed(task.buffer));
+
+                        conns.received(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            task.socket,
+                            buffer,
+                            read.size,
+                        );
+                    }
+                    CompletionType::ReadProvided(read) => {
+                        let buffer = read.buffer.map(Buffer::Fixed);
+
+                        conns.received(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            read.task.socket,
+                            buffer,
+                            read.size,
+                        );
+                    }
+                    CompletionType::RecvMulti(recv) => {
+                        conns.received_multi(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            recv.task.map(|task| task.socket),
+                            recv.buffer,
+                            recv.size,
+                        );
+                    }
+                    CompletionType::Write(write) => {
+                        let task = write.task;
+
+                        conns.written(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            task.socket,
+                            Buffer::Slice(task.buffer),
+                            task.start..task.end,
+                            write.size,
+                        );
+                    }
+                    CompletionType::WriteFixed(write) => {
+                        let task = write.task;
+
+                        conns.written(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            task.socket,
+                            Buffer::Fixed(task.buffer),
+                            task.start..task.end,
+                            write.size,
+                        );
+                    }
+                    CompletionType::SendZc(send) => {
+                        // The result and the notification usually arrive separately, but may be one.
+                        if let (Some(socket), Some(size)) = (send.socket, send.size) {
+                            conns.sent(
+                                &mut kio, &mut pool, &config, task_id, socket, size, send.len,
+                            );
+                        }
+
+                        if let Some(task) = send.task {
+                            conns.notified(&mut kio, &config, task.buffer);
+                        }
+                    }
+                    CompletionType::Poll(poll) => {
+                        conns.polled(&mut kio, &mut pool, &config, task_id, poll.events);
+                    }
+                    CompletionType::Splice(splice) => {
+                        conns.spliced(
+                            &mut kio,
+                            &mut pool,
+                            &config,
+                            task_id,
+                            splice.task,
+                            splice.size,
+                        );
+                    }
+                    CompletionType::Provide(provide) => {
+                        if let Err(err) = provide.result {
+                            println!("failed to provide buffer: {}", err);
+                        }
+                    }
+                    CompletionType::Sleep(_) => {
+                        conns.slept(&mut kio, &mut pool, &config, task_id);
+                    }
+                    CompletionType::Expired(expired) => match paused.remove(&expired.timer) {
+                        Some(listener) => accepts.push(kio.accept_multi(listener)),
+                        None => conns.expired(&mut kio, &mut pool, &config, expired.timer),
+                    },
+                    CompletionType::Timeout(_) => {
+                        // The timed out task is cancelled and closes the connection when it completes.
+                    }
+                    CompletionType::Cancel(_) => {}
+                    _ => {
+                        panic!("unknown completion")
+                    }
+                }
+            }
+
+            checker.tick(&mut kio, &mut pool);
+
+            if drain_deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                println!(
+                    "worker {} drain deadline reached, closing {} connections",
+                    self.id,
+                    conns.len()
+                );
+                conns.close_all(&mut kio, &mut pool, &config);
+                drain_deadline = None;
+            }
+        }
+
+        if conns.paused() > 0 {
+            println!(
+                "worker {} paused {} reads waiting for buffers",
+                self.id,
+                conns.paused()
+            );
+        }
+
+        if kio.backlog_peak() > 0 {
+            println!(
+                "worker {} queued up to {} tasks waiting for room in the ring",
+                self.id,
+                kio.backlog_peak()
+            );
+        }
+
+        // Wait for the remaining tasks so nothing is left referencing our sockets.
+        kio.shutdown()?;
+
+        Ok(())
+    }
+}
+
+// Pick the best way to move data that the kernel supports, falling back from the configured
+// one instead of failing on the first unsupported task, and log what was picked.
+fn path(id: usize, config: &Config, kio: &Kio) -> (config::BufferMode, usize) {
+    let capabilities = kio.capabilities();
+
+    let mut mode = config.buffers.mode;
+    if mode == config::BufferMode::Splice && !capabilities.splice {
+        println!(
+            "worker {} can't splice, falling back to provided buffers",
+            id
+        );
+        mode = config::BufferMode::Provided;
+    }
+
+    let provided = matches!(
+        mode,
+        config::BufferMode::Provided | config::BufferMode::Multishot
+    );
+    if provided && !capabilities.supports(opcode::ProvideBuffers::CODE) {
+        println!(
+            "worker {} can't provide buffers, falling back to fixed buffers",
+            id
+        );
+        mode = config::BufferMode::Fixed;
+    }
+
+    let mut zerocopy = config.buffers.zerocopy;
+    if zerocopy > 0 && !capabilities.send_zc {
+        println!(
+            "worker {} can't send without copying, falling back to copies",
+            id
+        );
+        zerocopy = 0;
+    }
+
+    // Everything else is emulated or just slower without kernel support.
+    let writes = match zerocopy {
+        0 => "copying writes".to_string(),
+        zerocopy => format!("sending writes of {}+ bytes without copying", zerocopy),
+    };
+
+    println!(
+        "worker {} using {} buffers, {}; kernel supports: {}",
+        id, mode, writes, capabilities
+    );
+
+    (mode, zerocopy)
+}
+
+// Pin the current thread to the CPU matching the worker, wrapping around if there are more workers.
+// This lines up with tcp::steer, which picks the listener matching the CPU.
+fn pin(id: usize) -> anyhow::Result<()> {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus < 1 {
+        anyhow::bail!("failed to count CPUs");
+    }
+
+    let mut set = CpuSet::new();
+    set.set(id % cpus as usize)?;
+
+    sched::sched_setaffinity(Pid::from_raw(0), &set)?;
+
+    Ok(())
+}
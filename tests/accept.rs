@@ -0,0 +1,85 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::{net, thread, time};
+
+use wisp::config::Config;
+use wisp::kio::completion::CompletionType;
+use wisp::kio::{buffer, Kio};
+
+#[test]
+fn connection_burst() {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    config.health.interval_ms = 0;
+
+    let addrs = common::spawn_proxy(config);
+    let addr = addrs[0];
+
+    // Connect all at once, so several are waiting on the same accept.
+    let clients: Vec<_> = (0..64u8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut stream = net::TcpStream::connect(addr).unwrap();
+                common::round_trip(&mut stream, &[i; 1024]);
+            })
+        })
+        .collect();
+
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+#[test]
+fn direct_accept() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    kio.register_files(4).unwrap();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    kio.accept_direct(listener);
+
+    let mut client = net::TcpStream::connect(addr).unwrap();
+
+    let (reader, writer) = match kio.wait().unwrap() {
+        (_, CompletionType::AcceptDirect(accept)) => {
+            assert_eq!(accept.peer, Some(client.local_addr().unwrap()));
+            accept.socket.unwrap()
+        }
+        _ => panic!("unexpected completion"),
+    };
+
+    let mut buffer = buffer::Slice::new(5);
+    buffer.copy_from_slice(b"hello");
+    kio.write(writer, buffer, ..);
+
+    match kio.wait().unwrap() {
+        (_, CompletionType::Write(write)) => assert_eq!(write.size.unwrap(), 5),
+        _ => panic!("unexpected completion"),
+    }
+
+    let mut buf = [0; 5];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Dropping both halves clears the slot the next time the runtime waits, closing the socket.
+    drop(reader);
+    kio.sleep(time::Duration::from_millis(1));
+    kio.wait().unwrap();
+
+    client.write_all(b"bye").unwrap();
+
+    let mut rest = Vec::new();
+    let _ = client.read_to_end(&mut rest);
+    assert!(rest.is_empty());
+}
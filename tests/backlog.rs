@@ -0,0 +1,113 @@
+use std::os::unix::io::AsRawFd;
+use std::{net, time};
+
+use wisp::kio::completion::CompletionType;
+use wisp::kio::{task, tcp, Kio};
+
+fn connected() -> (net::TcpStream, net::TcpStream) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    (client, server)
+}
+
+fn poll(socket: &net::TcpStream, events: libc::c_short) -> task::TaskType {
+    let poll = task::Poll {
+        target: tcp::Target::Fd(socket.as_raw_fd()),
+        events,
+    };
+
+    poll.into()
+}
+
+#[test]
+fn more_tasks_than_entries() {
+    let mut uring = io_uring::IoUring::new(8).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    // Far more than the ring holds, all pushed before waiting once.
+    let pending: Vec<_> = (0..4096)
+        .map(|_| kio.sleep(time::Duration::from_micros(10)))
+        .collect();
+
+    let mut done = Vec::new();
+    while done.len() < pending.len() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Sleep(sleep)) => {
+                sleep.result.unwrap();
+                done.push(id);
+            }
+            _ => panic!("unexpected completion"),
+        }
+    }
+
+    // Every task completed exactly once.
+    done.sort_unstable();
+    assert_eq!(done, pending);
+    assert_eq!(kio.backlog(), 0);
+}
+
+#[test]
+fn submitted_in_order() {
+    let mut uring = io_uring::IoUring::new(8).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let (client, _server) = connected();
+
+    // The socket is always writable, so each poll completes as soon as it's submitted.
+    let pending: Vec<_> = (0..4096)
+        .map(|_| kio.run(poll(&client, libc::POLLOUT)).detach())
+        .collect();
+
+    let mut done = Vec::new();
+    while done.len() < pending.len() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Poll(poll)) => {
+                poll.events.unwrap();
+                done.push(id);
+            }
+            _ => panic!("unexpected completion"),
+        }
+    }
+
+    assert_eq!(done, pending);
+}
+
+#[test]
+fn chains_stay_linked() {
+    let mut uring = io_uring::IoUring::new(4).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let (client, _server) = connected();
+
+    // Offset the chains, so they'd be split after the timeout.
+    kio.sleep(time::Duration::from_millis(1));
+    kio.sleep(time::Duration::from_millis(1));
+
+    // Nothing arrives, so every chain times out and the polls after it are cancelled.
+    // If a chain were split across submissions, those polls would run on their own and succeed.
+    let mut pending: Vec<_> = (0..256)
+        .flat_map(|_| {
+            kio.run_then(poll(&client, libc::POLLIN));
+            kio.timeout_then(time::Duration::from_millis(5));
+            let first = kio.run_then(poll(&client, libc::POLLOUT));
+            let second = kio.run(poll(&client, libc::POLLOUT)).detach();
+
+            vec![first, second]
+        })
+        .collect();
+
+    while !pending.is_empty() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Poll(poll)) if pending.contains(&id) => {
+                let err = poll.events.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+                pending.retain(|pending| *pending != id);
+            }
+            (_, CompletionType::Poll(_)) | (_, CompletionType::Timeout(_)) => {}
+            (_, CompletionType::Sleep(_)) => {}
+            _ => panic!("unexpected completion"),
+        }
+    }
+}
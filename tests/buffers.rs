@@ -0,0 +1,147 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::{net, thread, time};
+
+use wisp::config::{BufferMode, Config};
+use wisp::kio::Kio;
+
+fn proxy(mode: BufferMode, count: usize, zerocopy: usize) -> net::SocketAddr {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    config.health.interval_ms = 0;
+    config.buffers.count = count;
+    config.buffers.mode = mode;
+    config.buffers.zerocopy = zerocopy;
+
+    common::spawn_proxy(config)[0]
+}
+
+// Echo data through the proxy with a single buffer, so reads that need one wait for it.
+fn echo(mode: BufferMode, zerocopy: usize) {
+    let mut stream = net::TcpStream::connect(proxy(mode, 1, zerocopy)).unwrap();
+
+    for i in 0..16u8 {
+        let data = vec![i; 65536];
+        common::round_trip(&mut stream, &data);
+    }
+}
+
+#[test]
+fn slice_buffers() {
+    echo(BufferMode::Slice, 0);
+}
+
+#[test]
+fn provided_buffers() {
+    echo(BufferMode::Provided, 0);
+}
+
+#[test]
+fn multishot_buffers() {
+    echo(BufferMode::Multishot, 0);
+}
+
+#[test]
+fn fixed_buffers() {
+    echo(BufferMode::Fixed, 0);
+}
+
+#[test]
+fn splice_buffers() {
+    echo(BufferMode::Splice, 0);
+}
+
+// Zero-copy sends hold the only buffer until the kernel is done with it.
+#[test]
+fn zerocopy_buffers() {
+    echo(BufferMode::Provided, 1);
+    echo(BufferMode::Multishot, 1);
+    echo(BufferMode::Fixed, 1);
+}
+
+#[test]
+fn multishot_slow_reader() {
+    let addr = proxy(BufferMode::Multishot, 1024, 0);
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+
+    // Send more than the sockets can hold before reading any of it back, so the reads get ahead of
+    // the writes and have to stop until they catch up.
+    let data: Vec<u8> = (0..8 << 20).map(|i| i as u8).collect();
+
+    let mut writer = stream.try_clone().unwrap();
+    let sent = data.clone();
+    let sender = thread::spawn(move || writer.write_all(&sent).unwrap());
+
+    thread::sleep(time::Duration::from_millis(200));
+
+    let mut echoed = vec![0; data.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    sender.join().unwrap();
+
+    assert!(echoed == data);
+
+    // Every buffer was given back, so the proxy still works.
+    common::round_trip(&mut stream, &[1; 65536]);
+}
+
+#[test]
+fn multishot_idle() {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    config.health.interval_ms = 0;
+    config.buffers.mode = BufferMode::Multishot;
+    config.timeout.idle_ms = 200;
+
+    let addr = common::spawn_proxy(config)[0];
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+
+    // Activity keeps the connection open past the idle timeout.
+    for i in 0..4u8 {
+        common::round_trip(&mut stream, &[i; 1024]);
+        thread::sleep(time::Duration::from_millis(100));
+    }
+
+    // Then it's closed once nothing arrives for long enough.
+    let start = time::Instant::now();
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf).unwrap_or(0), 0);
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn buffer_ring() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+
+    // Registering the group again only works if the last ring was unregistered when dropped.
+    for _ in 0..2 {
+        let mut kio = Kio::new(&mut uring).unwrap();
+        if !kio.capabilities().buffer_ring {
+            return;
+        }
+
+        kio.register_ring(1024).unwrap();
+
+        // A ring that fails to register leaves the existing one alone.
+        for _ in 0..2 {
+            let err = kio.register_ring(1024).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        }
+    }
+
+    let mut kio = Kio::new(&mut uring).unwrap();
+    let err = kio.register_ring(1 << 16).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
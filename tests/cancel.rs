@@ -0,0 +1,131 @@
+use std::io::Write;
+use std::{net, time};
+
+use wisp::kio::completion::{self, CompletionType};
+use wisp::kio::{buffer, task, tcp, Kio};
+
+fn connected(kio: &mut Kio) -> (net::TcpStream, tcp::Reader, tcp::Writer) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let (reader, writer) = kio.register(stream);
+    (client, reader, writer)
+}
+
+// Wait for the read's completion, skipping the cancel's.
+fn wait_read(kio: &mut Kio, id: task::TaskId) -> completion::Read {
+    loop {
+        match kio.wait().unwrap() {
+            (task_id, CompletionType::Read(read)) if task_id == id => return read,
+            (_, CompletionType::Cancel(_)) => {}
+            _ => panic!("unexpected completion"),
+        }
+    }
+}
+
+#[test]
+fn cancel_during_read() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let (mut client, mut reader, _writer) = connected(&mut kio);
+
+    let mut sent = Vec::new();
+    let mut received = Vec::new();
+
+    // Race data arriving against the cancel, so some reads finish and some don't.
+    for i in 0..256 {
+        let buffer = buffer::Slice::new(1024);
+        let task = task::Read {
+            socket: reader,
+            buffer,
+        };
+        let handle = kio.run(task.into());
+        let id = handle.id();
+
+        if i % 2 == 0 {
+            let msg = [i as u8; 16];
+            client.write_all(&msg).unwrap();
+            sent.extend_from_slice(&msg);
+        }
+
+        drop(handle);
+
+        // The buffer lives on in the runtime until the kernel is done with it, and comes back.
+        let read = wait_read(&mut kio, id);
+        assert_eq!(read.task.buffer.len(), 1024);
+
+        match read.size {
+            Ok(size) => received.extend_from_slice(&read.task.buffer[..size]),
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::ECANCELED)),
+        }
+
+        reader = read.task.socket;
+    }
+
+    // Nothing was lost or overwritten by a cancelled read.
+    while received.len() < sent.len() {
+        let buffer = buffer::Slice::new(1024);
+        let id = kio.read(reader, buffer);
+
+        let read = wait_read(&mut kio, id);
+        let size = read.size.unwrap();
+        assert!(size > 0);
+
+        received.extend_from_slice(&read.task.buffer[..size]);
+        reader = read.task.socket;
+    }
+
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn explicit_cancel() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let (_client, reader, _writer) = connected(&mut kio);
+
+    let mut buffer = buffer::Slice::new(64);
+    buffer.copy_from_slice(&[7; 64]);
+
+    let task = task::Read {
+        socket: reader,
+        buffer,
+    };
+    let handle = kio.run(task.into());
+    let id = handle.id();
+    assert!(handle.is_running());
+
+    handle.cancel();
+
+    let read = wait_read(&mut kio, id);
+    let err = read.size.unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+    assert_eq!(&read.task.buffer[..], &[7; 64][..]);
+}
+
+#[test]
+fn stale_handle() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let first = kio.run(task::Sleep::new(time::Duration::from_millis(1)).into());
+    let (id, _) = kio.wait().unwrap();
+    assert_eq!(id, first.id());
+    assert!(!first.is_running());
+
+    // The id is reused, but dropping the old handle mustn't cancel the new task.
+    let second = kio
+        .run(task::Sleep::new(time::Duration::from_millis(1)).into())
+        .detach();
+    assert_eq!(second, id);
+
+    drop(first);
+
+    match kio.wait().unwrap() {
+        (id, CompletionType::Sleep(sleep)) if id == second => sleep.result.unwrap(),
+        _ => panic!("unexpected completion"),
+    }
+}
@@ -0,0 +1,191 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::{net, time};
+
+use wisp::kio::completion::CompletionType;
+use wisp::kio::{buffer, task, tcp, Kio};
+
+#[test]
+fn connect_write_read() {
+    let addr = common::echo_backend("127.0.0.1:0");
+
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let (reader, writer) = tcp::split(tcp::socket(&addr).unwrap());
+
+    let mut msg = buffer::Slice::new(5);
+    msg.copy_from_slice(b"hello");
+
+    // The write only starts once the connect succeeded.
+    let links = kio
+        .chain()
+        .connect(reader, addr)
+        .timeout(time::Duration::from_secs(5))
+        .write(writer, msg, ..)
+        .submit();
+
+    let ids = links.ids();
+    assert_eq!(ids.len(), 2);
+
+    let mut reader = None;
+
+    while !links.is_done() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Connect(connect)) => {
+                assert_eq!(id, ids[0]);
+                connect.result.unwrap();
+                reader = Some(connect.task.socket);
+            }
+            (id, CompletionType::Write(write)) => {
+                assert_eq!(id, ids[1]);
+                assert_eq!(write.size.unwrap(), 5);
+            }
+            (_, CompletionType::Timeout(_)) => {}
+            _ => panic!("unexpected completion"),
+        }
+    }
+
+    assert!(links.failed().is_none());
+
+    kio.read(reader.unwrap(), buffer::Slice::new(5));
+
+    match kio.wait().unwrap() {
+        (_, CompletionType::Read(read)) => {
+            assert_eq!(read.size.unwrap(), 5);
+            assert_eq!(&read.task.buffer[..], b"hello");
+        }
+        _ => panic!("unexpected completion"),
+    }
+}
+
+#[test]
+fn failed_link() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let (reader, writer) = tcp::split(stream);
+
+    let mut msg = buffer::Slice::new(3);
+    msg.copy_from_slice(b"bye");
+
+    // Nothing arrives, so the read times out and the write is cancelled.
+    let links = kio
+        .chain()
+        .sleep(time::Duration::from_millis(1))
+        .read(reader, buffer::Slice::new(16))
+        .timeout(time::Duration::from_millis(10))
+        .write(writer, msg, ..)
+        .submit();
+
+    let ids = links.ids();
+
+    while !links.is_done() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Write(write)) => {
+                assert_eq!(id, ids[2]);
+                let err = write.size.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+            }
+            (_, CompletionType::Sleep(_)) | (_, CompletionType::Read(_)) => {}
+            (_, CompletionType::Timeout(_)) => {}
+            _ => panic!("unexpected completion"),
+        }
+    }
+
+    let failure = links.failed().unwrap();
+    assert_eq!(failure.index, 1);
+    assert_eq!(failure.id, ids[1]);
+    assert_eq!(failure.error.raw_os_error(), Some(libc::ETIMEDOUT));
+
+    // The write never happened, so the client only sees the socket close.
+    let mut buf = [0; 3];
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn partial_link() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let (reader, writer) = tcp::split(stream);
+
+    client.write_all(b"hi").unwrap();
+
+    let mut msg = buffer::Slice::new(2);
+    msg.copy_from_slice(b"ok");
+
+    // The read is short, but the write runs anyway.
+    let read = task::Read {
+        socket: reader,
+        buffer: buffer::Slice::new(16),
+    };
+
+    let links = kio
+        .chain()
+        .partial(read.into())
+        .write(writer, msg, ..)
+        .submit();
+
+    while !links.is_done() {
+        match kio.wait().unwrap() {
+            (_, CompletionType::Read(read)) => assert_eq!(read.size.unwrap(), 2),
+            (_, CompletionType::Write(write)) => assert_eq!(write.size.unwrap(), 2),
+            _ => panic!("unexpected completion"),
+        }
+    }
+
+    assert!(links.failed().is_none());
+
+    let mut buf = [0; 2];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ok");
+}
+
+#[test]
+fn never_split() {
+    // Room for only four tasks, so the chain won't fit after the sleeps.
+    let mut uring = io_uring::IoUring::new(4).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    for _ in 0..3 {
+        kio.sleep(time::Duration::from_millis(1));
+    }
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let (reader, _writer) = tcp::split(stream);
+
+    // If the chain were split, the sleeps after the read would run on their own.
+    let links = kio
+        .chain()
+        .read(reader, buffer::Slice::new(16))
+        .timeout(time::Duration::from_millis(10))
+        .sleep(time::Duration::from_millis(1))
+        .sleep(time::Duration::from_millis(1))
+        .submit();
+
+    let ids = links.ids();
+
+    while !links.is_done() {
+        match kio.wait().unwrap() {
+            (id, CompletionType::Sleep(sleep)) if ids.contains(&id) => {
+                let err = sleep.result.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+            }
+            _ => {}
+        }
+    }
+
+    let failure = links.failed().unwrap();
+    assert_eq!(failure.index, 0);
+    assert_eq!(failure.error.raw_os_error(), Some(libc::ETIMEDOUT));
+}
@@ -0,0 +1,191 @@
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc;
+use std::{net, process, thread, time};
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use wisp::config::Config;
+use wisp::proxy::Proxy;
+
+// Start a backend that echoes everything back, returning the bound address.
+pub fn echo_backend(addr: &str) -> net::SocketAddr {
+    let listener = net::TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            if stream.write_all(&buf[..n]).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+// An address with nothing listening on it, at least until it's bound again.
+pub fn dead() -> net::SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Listen on any local port and proxy to the backends, with everything else left as the default.
+pub fn config(backends: &[net::SocketAddr]) -> Config {
+    Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: backends.iter().map(|addr| (*addr).into()).collect(),
+        ..Config::default()
+    }
+}
+
+// Run a proxy on a background thread, returning the addresses it's listening on.
+pub fn spawn_proxy(config: Config) -> Vec<net::SocketAddr> {
+    let proxy = Proxy::new(config).unwrap();
+    let addrs = proxy.local_addrs().unwrap();
+
+    thread::spawn(move || proxy.run().unwrap());
+
+    addrs
+}
+
+// Write the message through the proxy and expect it to be echoed back.
+pub fn round_trip(stream: &mut net::TcpStream, msg: &[u8]) {
+    stream.write_all(msg).unwrap();
+
+    let mut buf = vec![0; msg.len()];
+    stream.read_exact(&mut buf).unwrap();
+
+    assert_eq!(buf, msg);
+}
+
+// Read until the peer closes or resets the connection, returning how long that took, or None if
+// it's still open after a few seconds. Anything received in the meantime is discarded.
+pub fn closed(stream: &mut net::TcpStream) -> Option<time::Duration> {
+    let start = time::Instant::now();
+    stream
+        .set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+
+    let mut buf = [0; 65536];
+
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Some(start.elapsed()),
+            Ok(_) => continue,
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return None
+            }
+            Err(_) => return Some(start.elapsed()),
+        }
+    }
+}
+
+// The wisp binary running in a child process, so it can be sent signals without affecting the
+// tests. It's killed when dropped.
+pub struct Wisp {
+    pub addr: net::SocketAddr, // the first address it's listening on
+    child: process::Child,
+    output: mpsc::Receiver<String>, // lines printed to stdout
+}
+
+impl Wisp {
+    // Start wisp with the arguments and wait until it's listening.
+    pub fn start(args: &[&str]) -> Self {
+        let mut child = process::Command::new(env!("CARGO_BIN_EXE_wisp"))
+            .args(args)
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let stdout = child.stdout.take().unwrap();
+        let (tx, output) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in io::BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut wisp = Self {
+            addr: ([0, 0, 0, 0], 0).into(),
+            child,
+            output,
+        };
+
+        let line = wisp.expect("listen ");
+        wisp.addr = line["listen ".len()..].parse().unwrap();
+
+        wisp
+    }
+
+    // Wait for a line starting with the prefix, skipping any others, and return it.
+    pub fn expect(&self, prefix: &str) -> String {
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+
+        loop {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            match self.output.recv_timeout(timeout) {
+                Ok(line) if line.starts_with(prefix) => return line,
+                Ok(_) => {}
+                Err(_) => panic!("wisp didn't print {:?}", prefix),
+            }
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn signal(&self, signal: Signal) {
+        signal::kill(Pid::from_raw(self.child.id() as i32), signal).unwrap();
+    }
+
+    // Wait for it to exit cleanly, returning how long that took.
+    pub fn wait(&mut self) -> time::Duration {
+        let start = time::Instant::now();
+
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                assert!(status.success(), "wisp exited with {}", status);
+                return start.elapsed();
+            }
+
+            assert!(
+                start.elapsed() < time::Duration::from_secs(5),
+                "wisp didn't exit"
+            );
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Wisp {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
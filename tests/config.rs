@@ -0,0 +1,231 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, net, process};
+
+use structopt::StructOpt;
+
+use wisp::config::{Args, Backend, Balance, BufferMode, Config};
+
+// Load the config from the command line, with the file if given.
+fn load(toml: Option<&str>, args: &[&str]) -> anyhow::Result<Config> {
+    let mut argv = vec!["wisp".to_string()];
+
+    if let Some(toml) = toml {
+        // Tests run in parallel, so each gets its own file.
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "wisp-config-{}-{}.toml",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let path = env::temp_dir().join(name);
+        fs::write(&path, toml).unwrap();
+
+        argv.push("--config".to_string());
+        argv.push(path.to_str().unwrap().to_string());
+    }
+
+    argv.extend(args.iter().map(|arg| arg.to_string()));
+
+    Config::load(Args::from_iter(argv))
+}
+
+// Breaks a valid config, along with part of the error it should cause.
+type Invalid = (&'static str, fn(&mut Config));
+
+fn addr(s: &str) -> net::SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn parse() {
+    let config = Config::parse(
+        r#"
+        listen = ["127.0.0.1:8080", "[::]:8080"]
+        backend = ["10.0.0.1:80", "10.0.0.2:80@3", { addr = "[::1]:80", weight = 5 }]
+        balance = "least-connections"
+        v6_only = true
+
+        [timeout]
+        idle_ms = 1000
+
+        [buffers]
+        mode = "multishot"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.listen,
+        vec![addr("127.0.0.1:8080"), addr("[::]:8080")]
+    );
+    assert_eq!(
+        config.backend,
+        vec![
+            Backend {
+                addr: addr("10.0.0.1:80"),
+                weight: 1
+            },
+            Backend {
+                addr: addr("10.0.0.2:80"),
+                weight: 3
+            },
+            Backend {
+                addr: addr("[::1]:80"),
+                weight: 5
+            },
+        ]
+    );
+    assert_eq!(config.balance, Balance::LeastConnections);
+    assert!(config.v6_only);
+    assert_eq!(config.timeout.idle_ms, 1000);
+    assert_eq!(config.buffers.mode, BufferMode::Multishot);
+
+    // Everything else keeps its default.
+    let default = Config::default();
+    assert_eq!(config.timeout.connect_ms, default.timeout.connect_ms);
+    assert_eq!(config.ring.entries, default.ring.entries);
+}
+
+#[test]
+fn parse_errors() {
+    // Unknown keys are typos, not ignored.
+    assert!(Config::parse("listen = []\nbakcend = []").is_err());
+    assert!(Config::parse("[timeout]\nidle = 5").is_err());
+
+    assert!(Config::parse(r#"balance = "fastest""#).is_err());
+    assert!(Config::parse(r#"backend = ["10.0.0.1:80@heavy"]"#).is_err());
+    assert!(Config::parse(r#"backend = ["localhost:80"]"#).is_err());
+}
+
+#[test]
+fn backend_weight() {
+    let backend: Backend = "10.0.0.1:80@2".parse().unwrap();
+    assert_eq!(backend.addr, addr("10.0.0.1:80"));
+    assert_eq!(backend.weight, 2);
+
+    // The weight follows the last @, so IPv6 addresses are unaffected.
+    let backend: Backend = "[::1]:80@7".parse().unwrap();
+    assert_eq!(backend.addr, addr("[::1]:80"));
+    assert_eq!(backend.weight, 7);
+
+    let backend: Backend = "[::1]:80".parse().unwrap();
+    assert_eq!(backend.weight, 1);
+
+    assert!("10.0.0.1:80@".parse::<Backend>().is_err());
+    assert!("10.0.0.1:80@-1".parse::<Backend>().is_err());
+}
+
+#[test]
+fn cli_overrides_file() {
+    let toml = r#"
+        listen = ["127.0.0.1:8080"]
+        backend = ["10.0.0.1:80"]
+        v6_only = true
+
+        [timeout]
+        idle_ms = 1000
+        write_ms = 2000
+
+        [ring]
+        sqpoll = true
+        single_issuer = true
+        share = true
+
+        [workers]
+        pin = true
+        steer = true
+    "#;
+
+    // Without arguments, the file is used as it is.
+    let config = load(Some(toml), &[]).unwrap();
+    assert_eq!(config.listen, vec![addr("127.0.0.1:8080")]);
+    assert!(config.v6_only && config.ring.sqpoll && config.workers.steer);
+
+    let config = load(
+        Some(toml),
+        &[
+            "--listen",
+            "127.0.0.1:9090",
+            "--backend",
+            "10.0.0.2:80@4",
+            "--backend",
+            "10.0.0.3:80",
+            "--idle-timeout-ms",
+            "5",
+            "--no-v6-only",
+            "--no-ring-sqpoll",
+            "--ring-completions",
+            "4096",
+            "--ring-sqpoll-idle-ms",
+            "50",
+            "--ring-coop-taskrun",
+            "--no-ring-single-issuer",
+            "--no-ring-share",
+            "--no-pin-workers",
+            "--no-steer",
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(config.listen, vec![addr("127.0.0.1:9090")]);
+    assert_eq!(config.backend.len(), 2);
+    assert_eq!(config.backend[0].weight, 4);
+    assert_eq!(config.timeout.idle_ms, 5);
+    assert!(!config.v6_only);
+    assert!(!config.ring.sqpoll);
+    assert_eq!(config.ring.completions, 4096);
+    assert_eq!(config.ring.sqpoll_idle_ms, 50);
+    assert!(config.ring.coop_taskrun);
+    assert!(!config.ring.single_issuer && !config.ring.defer_taskrun && !config.ring.share);
+    assert!(!config.workers.pin && !config.workers.steer);
+
+    // What wasn't given on the command line still comes from the file.
+    assert_eq!(config.timeout.write_ms, 2000);
+}
+
+#[test]
+fn last_switch_wins() {
+    let config = load(None, &["--v6-only", "--no-v6-only"]).unwrap();
+    assert!(!config.v6_only);
+
+    let config = load(None, &["--no-v6-only", "--v6-only"]).unwrap();
+    assert!(config.v6_only);
+}
+
+#[test]
+fn validate() {
+    assert!(Config::default().validate().is_ok());
+
+    let invalid: Vec<Invalid> = vec![
+        ("no listen addresses", |config| config.listen.clear()),
+        ("no backend addresses", |config| config.backend.clear()),
+        ("backend weight", |config| config.backend[0].weight = 0),
+        ("backend weight", |config| config.backend[0].weight = 1001),
+        ("health rise", |config| config.health.fall = 0),
+        ("retry attempts", |config| config.retry.attempts = 0),
+        ("ring entries", |config| config.ring.entries = 0),
+        ("ring completions", |config| config.ring.completions = 1),
+        ("requires sqpoll", |config| config.ring.sqpoll_cpu = Some(0)),
+        ("single issuer", |config| config.ring.defer_taskrun = true),
+        ("buffer size", |config| config.buffers.size = 0),
+        ("too many buffers", |config| config.buffers.count = 1 << 17),
+        ("pinned workers", |config| config.workers.steer = true),
+    ];
+
+    for (error, change) in invalid {
+        let mut config = Config::default();
+        change(&mut config);
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(error), "{:?} doesn't mention {:?}", err, error);
+    }
+
+    // The same checks run after loading.
+    assert!(load(None, &["--steer"]).is_err());
+    assert!(load(None, &["--ring-sqpoll-cpu", "0"]).is_err());
+    assert!(load(None, &["--ring-defer-taskrun"]).is_err());
+
+    let config = load(None, &["--ring-sqpoll", "--ring-sqpoll-cpu", "0"]).unwrap();
+    assert_eq!(config.ring.sqpoll_cpu, Some(0));
+}
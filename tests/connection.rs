@@ -0,0 +1,134 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::{net, thread, time};
+
+use wisp::config::Config;
+use wisp::kio::tcp;
+
+// Run a proxy in front of a backend that handles a single connection with the given function.
+fn proxy<F>(handler: F) -> net::SocketAddr
+where
+    F: FnOnce(net::TcpStream) + Send + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        handler(stream);
+    });
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    // Don't let health checks use up the single connection.
+    config.health.interval_ms = 0;
+
+    common::spawn_proxy(config)[0]
+}
+
+fn connect(addr: net::SocketAddr) -> net::TcpStream {
+    let stream = net::TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+#[test]
+fn client_eof_first() {
+    let addr = proxy(|mut stream| {
+        // Echo until the client is done, then send a trailer and close.
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        stream.write_all(&data).unwrap();
+        stream.write_all(b"trailer").unwrap();
+    });
+
+    let mut stream = connect(addr);
+
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    stream.write_all(&data).unwrap();
+    stream.shutdown(net::Shutdown::Write).unwrap();
+
+    // The response still arrives after our half of the connection is closed.
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    assert_eq!(response.len(), data.len() + b"trailer".len());
+    assert_eq!(&response[..data.len()], &data[..]);
+    assert_eq!(&response[data.len()..], b"trailer");
+}
+
+#[test]
+fn backend_eof_first() {
+    let (tx, rx) = mpsc::channel();
+
+    let addr = proxy(move |mut stream| {
+        stream.write_all(b"hello").unwrap();
+        stream.shutdown(net::Shutdown::Write).unwrap();
+
+        // Keep reading what the client sends after we're done.
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        tx.send(data).unwrap();
+    });
+
+    let mut stream = connect(addr);
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(response, b"hello");
+
+    stream.write_all(b"goodbye").unwrap();
+    stream.shutdown(net::Shutdown::Write).unwrap();
+
+    let received = rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    assert_eq!(received, b"goodbye");
+}
+
+#[test]
+fn client_reset() {
+    let (tx, rx) = mpsc::channel();
+
+    let addr = proxy(move |mut stream| {
+        stream
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+
+        tx.send(common::closed(&mut stream).is_some()).unwrap();
+    });
+
+    let mut stream = connect(addr);
+    common::round_trip(&mut stream, b"hello");
+
+    tcp::abort(stream.as_raw_fd()).unwrap();
+    drop(stream);
+
+    assert!(rx.recv_timeout(time::Duration::from_secs(5)).unwrap());
+}
+
+#[test]
+fn backend_reset() {
+    let addr = proxy(|mut stream| {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+
+        tcp::abort(stream.as_raw_fd()).unwrap();
+    });
+
+    let mut stream = connect(addr);
+    stream.write_all(b"hello").unwrap();
+
+    assert!(common::closed(&mut stream).is_some());
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::{net, thread, time};
+
+use nix::sys::signal::Signal;
+
+use common::Wisp;
+
+// Start wisp in front of an echo backend, with a connection that's already been proxied so the
+// signals are being handled.
+fn start(args: &[&str]) -> (Wisp, net::TcpStream) {
+    let backend = common::echo_backend("127.0.0.1:0").to_string();
+
+    let mut argv = vec![
+        "--listen",
+        "127.0.0.1:0",
+        "--backend",
+        &backend,
+        "--health-interval-ms",
+        "0",
+    ];
+    argv.extend(args);
+
+    let wisp = Wisp::start(&argv);
+
+    let mut stream = net::TcpStream::connect(wisp.addr).unwrap();
+    stream
+        .set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+    common::round_trip(&mut stream, b"hello");
+
+    (wisp, stream)
+}
+
+// Returns true once new connections are refused, waiting a little for the listener to close.
+fn refused(addr: net::SocketAddr) -> bool {
+    for _ in 0..100 {
+        if net::TcpStream::connect(addr).is_err() {
+            return true;
+        }
+
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    false
+}
+
+#[test]
+fn drain() {
+    let (mut wisp, mut stream) = start(&[]);
+
+    wisp.signal(Signal::SIGTERM);
+    wisp.expect("worker 0 draining 1 connections");
+
+    // No new connections, but the existing one keeps working until the client is done.
+    assert!(refused(wisp.addr));
+    thread::sleep(time::Duration::from_millis(100));
+    common::round_trip(&mut stream, b"still here");
+
+    stream.shutdown(net::Shutdown::Write).unwrap();
+    assert!(common::closed(&mut stream).is_some());
+
+    wisp.expect("shutdown complete");
+    wisp.wait();
+}
+
+#[test]
+fn drain_deadline() {
+    let (mut wisp, mut stream) = start(&["--drain-timeout-ms", "200"]);
+
+    let start = time::Instant::now();
+    wisp.signal(Signal::SIGTERM);
+
+    // Connections still open at the deadline are closed.
+    wisp.expect("worker 0 drain deadline reached, closing 1 connections");
+    assert!(common::closed(&mut stream).is_some());
+
+    wisp.wait();
+    assert!(start.elapsed() >= time::Duration::from_millis(200));
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn second_signal() {
+    let (mut wisp, mut stream) = start(&[]);
+
+    wisp.signal(Signal::SIGTERM);
+    wisp.expect("worker 0 draining 1 connections");
+
+    // Skips the rest of the drain, rather than waiting for the default 30 seconds.
+    wisp.signal(Signal::SIGINT);
+    wisp.expect("worker 0 closing 1 connections");
+    assert!(common::closed(&mut stream).is_some());
+
+    assert!(wisp.wait() < time::Duration::from_secs(2));
+}
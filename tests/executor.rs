@@ -0,0 +1,218 @@
+mod common;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::{net, thread, time};
+
+use wisp::kio::executor::{Executor, Spawner};
+use wisp::kio::{buffer, task, tcp, Kio};
+
+// Echo everything back until the client closes, handing the socket and buffer through each task.
+async fn echo(spawner: Spawner<'_>, mut reader: tcp::Reader, mut writer: tcp::Writer) {
+    let mut buffer = buffer::Slice::new(4096);
+
+    loop {
+        let read = spawner.read(reader, buffer).await;
+        reader = read.task.socket;
+        buffer = read.task.buffer;
+
+        let size = match read.size {
+            Ok(0) | Err(_) => return,
+            Ok(size) => size,
+        };
+
+        let write = spawner.write(writer, buffer, ..size).await;
+        writer = write.task.socket;
+        buffer = write.task.buffer;
+
+        if write.size.is_err() {
+            return;
+        }
+    }
+}
+
+#[test]
+fn echo_server() {
+    let mut uring = io_uring::IoUring::new(64).unwrap();
+    let mut executor = Executor::new(Kio::new(&mut uring).unwrap()).unwrap();
+    let spawner = executor.spawner();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let clients: Vec<_> = (0..4u8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut stream = net::TcpStream::connect(addr).unwrap();
+                common::round_trip(&mut stream, &[i; 65536]);
+            })
+        })
+        .collect();
+
+    let done = Rc::new(Cell::new(0));
+
+    executor
+        .block_on(async {
+            let mut listener = listener;
+
+            for _ in 0..clients.len() {
+                let accept = spawner.accept(listener).await;
+                listener = accept.task.unwrap().socket;
+
+                let (reader, writer) = spawner.kio().register(accept.socket.unwrap());
+                let (echoer, done) = (spawner.clone(), done.clone());
+
+                spawner.spawn(async move {
+                    echo(echoer, reader, writer).await;
+                    done.set(done.get() + 1);
+                });
+            }
+
+            // The handlers are spawned, so wait for the clients to close.
+            while done.get() < clients.len() {
+                spawner.sleep(time::Duration::from_millis(1)).await;
+            }
+        })
+        .unwrap();
+
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+#[test]
+fn read_timeout() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut executor = Executor::new(Kio::new(&mut uring).unwrap()).unwrap();
+    let spawner = executor.spawner();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let (reader, _writer) = spawner.kio().register(stream);
+
+    // Nothing is sent, so the read is cancelled and the buffer comes back.
+    let read = executor
+        .block_on(async {
+            let buffer = buffer::Slice::new(1024);
+            spawner
+                .read(reader, buffer)
+                .timeout(time::Duration::from_millis(20))
+                .await
+        })
+        .unwrap();
+
+    let err = read.size.unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+    assert_eq!(read.task.buffer.len(), 1024);
+}
+
+#[test]
+fn drop_with_read_pending() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut executor = Executor::new(Kio::new(&mut uring).unwrap()).unwrap();
+    let spawner = executor.spawner();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let (reader, writer) = spawner.kio().register(stream);
+
+    // The read is left pending in the spawned future once block_on returns.
+    let reading = spawner.clone();
+    spawner.spawn(async move {
+        let _writer = writer;
+        reading.read(reader, buffer::Slice::new(1024)).await;
+        unreachable!("nothing is sent");
+    });
+
+    executor
+        .block_on(spawner.sleep(time::Duration::from_millis(20)))
+        .unwrap();
+
+    drop(spawner);
+    drop(executor);
+
+    // The kernel let go of the socket once the read was cancelled, so it's closed.
+    client
+        .set_read_timeout(Some(time::Duration::from_secs(1)))
+        .unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+
+    // And nothing completes into the freed buffer.
+    let _ = client.write_all(&[1; 64]);
+    thread::sleep(time::Duration::from_millis(20));
+    assert!(uring.completion().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "repeating accept")]
+fn repeating_accept() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let executor = Executor::new(Kio::new(&mut uring).unwrap()).unwrap();
+
+    // Only the first connection would be resolved, so it's refused up front.
+    let task = task::Accept {
+        socket: net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        repeat: true,
+        multishot: false,
+    };
+    drop(executor.spawner().run(task));
+}
+
+// Resolves once another thread sets it, without anything on the ring.
+#[derive(Clone, Default)]
+struct Flag(Arc<Mutex<(bool, Option<Waker>)>>);
+
+impl Flag {
+    fn set(&self) {
+        let mut flag = self.0.lock().unwrap();
+        flag.0 = true;
+
+        if let Some(waker) = flag.1.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for Flag {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut flag = self.0.lock().unwrap();
+        if flag.0 {
+            return Poll::Ready(());
+        }
+
+        flag.1 = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[test]
+fn wake_from_thread() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut executor = Executor::new(Kio::new(&mut uring).unwrap()).unwrap();
+
+    for _ in 0..3 {
+        let flag = Flag::default();
+        let setter = flag.clone();
+
+        let thread = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            setter.set();
+        });
+
+        // The executor is waiting on the ring when it's woken.
+        executor.block_on(flag).unwrap();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::io::Read;
+use std::net;
+
+use wisp::config::Config;
+
+// Echo through the proxy with room for the given number of registered sockets.
+fn echo(files: u32) {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    config.health.interval_ms = 0;
+    config.ring.files = files;
+
+    let addrs = common::spawn_proxy(config);
+
+    // Each connection gives its slots back once closed, for the next one to use.
+    for i in 0..4u8 {
+        let mut stream = net::TcpStream::connect(addrs[0]).unwrap();
+        common::round_trip(&mut stream, &[i; 1024]);
+
+        stream.shutdown(net::Shutdown::Write).unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
+
+#[test]
+fn registered_files() {
+    echo(2);
+}
+
+#[test]
+fn files_table_full() {
+    // The backend socket falls back to its descriptor.
+    echo(1);
+}
+
+#[test]
+fn unregistered_files() {
+    echo(0);
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use std::{env, net, process, thread, time};
+
+use wisp::config::{self, Config};
+use wisp::proxy::Proxy;
+
+#[test]
+fn handoff_listeners() {
+    let backend = common::echo_backend("127.0.0.1:0");
+    let handoff = env::temp_dir().join(format!("wisp-handoff-{}.sock", process::id()));
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        handoff: Some(handoff.clone()),
+        ..Config::default()
+    };
+
+    let old = Proxy::new(config).unwrap();
+    let addrs = old.local_addrs().unwrap();
+    let old = thread::spawn(move || old.run());
+
+    let mut existing = net::TcpStream::connect(addrs[0]).unwrap();
+    common::round_trip(&mut existing, b"before");
+
+    // The new process takes over the same address instead of binding it again.
+    config = Config {
+        listen: addrs.clone(),
+        backend: vec![backend.into()],
+        handoff: Some(handoff),
+        ..Config::default()
+    };
+
+    let new = Proxy::new(config).unwrap();
+    assert_eq!(new.local_addrs().unwrap(), addrs);
+    thread::spawn(move || new.run().unwrap());
+
+    // Connections are never refused during the handoff.
+    let mut stream = net::TcpStream::connect(addrs[0]).unwrap();
+    common::round_trip(&mut stream, b"during");
+
+    // The old process keeps serving the existing connection while draining.
+    common::round_trip(&mut existing, b"draining");
+    assert!(!old.is_finished());
+
+    // And exits once they're closed.
+    drop(existing);
+    drop(stream);
+
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    while !old.is_finished() {
+        assert!(time::Instant::now() < deadline, "old process didn't exit");
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    old.join().unwrap().unwrap();
+
+    // Only the new process is left accepting.
+    let mut stream = net::TcpStream::connect(addrs[0]).unwrap();
+    common::round_trip(&mut stream, b"after");
+}
+
+#[test]
+fn handoff_many_listeners() {
+    let backend = common::echo_backend("127.0.0.1:0");
+    let handoff = env::temp_dir().join(format!("wisp-handoff-many-{}.sock", process::id()));
+
+    // A listener per worker for each address, more than fit in one message.
+    let workers = || config::Workers {
+        count: 3,
+        ..config::Workers::default()
+    };
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap(); 100],
+        backend: vec![backend.into()],
+        handoff: Some(handoff.clone()),
+        workers: workers(),
+        ..Config::default()
+    };
+
+    let old = Proxy::new(config).unwrap();
+    let addrs = old.local_addrs().unwrap();
+    let old = thread::spawn(move || old.run());
+
+    config = Config {
+        listen: addrs.clone(),
+        backend: vec![backend.into()],
+        handoff: Some(handoff),
+        workers: workers(),
+        ..Config::default()
+    };
+
+    // Every listener is taken over, rather than any being bound again.
+    let new = Proxy::new(config).unwrap();
+    assert_eq!(new.local_addrs().unwrap(), addrs);
+    thread::spawn(move || new.run().unwrap());
+
+    // The old process stops once the new one has them all, and nothing was open to drain.
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    while !old.is_finished() {
+        assert!(time::Instant::now() < deadline, "old process didn't exit");
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    old.join().unwrap().unwrap();
+
+    for addr in &addrs {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        common::round_trip(&mut stream, b"after");
+    }
+}
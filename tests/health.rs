@@ -0,0 +1,178 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::{net, thread, time};
+
+use wisp::backend::Pool;
+use wisp::config::Config;
+
+use common::dead;
+
+// Start an HTTP backend that answers health checks with the status, and sends its name to every
+// other connection.
+fn backend(name: &'static [u8], status: Arc<AtomicU16>) -> net::SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    serve(listener, name, status);
+    addr
+}
+
+fn serve(listener: net::TcpListener, name: &'static [u8], status: Arc<AtomicU16>) {
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = [0; 1024];
+            let size = stream.read(&mut request).unwrap_or(0);
+
+            if request[..size].starts_with(b"GET /health ") {
+                let status = status.load(Ordering::Relaxed);
+                let _ = write!(stream, "HTTP/1.1 {} Status\r\n\r\n", status);
+            } else {
+                let _ = stream.write_all(name);
+            }
+        }
+    });
+}
+
+// Check quickly, so backends go up and down within a test.
+fn config(backends: &[net::SocketAddr]) -> Config {
+    let mut config = common::config(backends);
+
+    config.health.interval_ms = 50;
+    config.health.rise = 1;
+    config.health.fall = 1;
+
+    // Any connection routed to a down backend fails instead of moving on to another.
+    config.retry.attempts = 1;
+
+    config
+}
+
+// Send a request through the proxy and return the name of the backend that answered, or None if
+// the connection failed.
+fn name(addr: net::SocketAddr) -> Option<Vec<u8>> {
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"hello").unwrap();
+
+    let mut name = Vec::new();
+    stream.read_to_end(&mut name).ok()?;
+
+    Some(name).filter(|name| !name.is_empty())
+}
+
+fn names(addr: net::SocketAddr, count: usize) -> Vec<Option<Vec<u8>>> {
+    (0..count).map(|_| name(addr)).collect()
+}
+
+fn settle() {
+    thread::sleep(time::Duration::from_millis(300));
+}
+
+#[test]
+fn tcp_check() {
+    let good = backend(b"good", Arc::new(AtomicU16::new(200)));
+    let dead = dead();
+
+    let addr = common::spawn_proxy(config(&[good, dead]))[0];
+
+    // The dead backend is found by connecting to it, before any client does.
+    settle();
+    assert!(names(addr, 6)
+        .iter()
+        .all(|name| name.as_deref() == Some(b"good")));
+}
+
+#[test]
+fn http_check() {
+    let good = backend(b"good", Arc::new(AtomicU16::new(200)));
+    let bad = backend(b"bad", Arc::new(AtomicU16::new(503)));
+    let dead = dead();
+
+    let mut config = config(&[good, bad, dead]);
+    config.health.path = Some("/health".to_string());
+
+    let addr = common::spawn_proxy(config)[0];
+
+    // Accepting connections isn't enough, the status has to match.
+    settle();
+    assert!(names(addr, 6)
+        .iter()
+        .all(|name| name.as_deref() == Some(b"good")));
+}
+
+#[test]
+fn recovers() {
+    let status = Arc::new(AtomicU16::new(503));
+    let flaky = backend(b"flaky", status.clone());
+    let good = backend(b"good", Arc::new(AtomicU16::new(200)));
+
+    let mut config = config(&[flaky, good]);
+    config.health.path = Some("/health".to_string());
+
+    let addr = common::spawn_proxy(config)[0];
+
+    settle();
+    assert!(!names(addr, 4).contains(&Some(b"flaky".to_vec())));
+
+    // Checks keep running against a down backend, so it's used again once it passes.
+    status.store(200, Ordering::Relaxed);
+    settle();
+    assert!(names(addr, 4).contains(&Some(b"flaky".to_vec())));
+}
+
+#[test]
+fn rise_and_fall() {
+    let mut config = config(&["10.0.0.1:80".parse().unwrap()]);
+    config.health.rise = 2;
+    config.health.fall = 3;
+
+    let mut pool = Pool::from_config(&config);
+    let (id, _) = pool.iter().next().unwrap();
+
+    let mut report = |healthy| {
+        pool.report(id, healthy);
+        pool.get(id).unwrap().up()
+    };
+
+    // A success in between resets the failures.
+    assert!(report(false));
+    assert!(report(false));
+    assert!(report(true));
+    assert!(report(false));
+    assert!(report(false));
+    assert!(!report(false));
+
+    // Same for a failure in between successes.
+    assert!(!report(true));
+    assert!(!report(false));
+    assert!(!report(true));
+    assert!(report(true));
+}
+
+#[test]
+fn failures_without_checks() {
+    let dead = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let revived = dead.local_addr().unwrap();
+    drop(dead);
+
+    let good = backend(b"good", Arc::new(AtomicU16::new(200)));
+
+    let mut config = config(&[revived, good]);
+    config.health.interval_ms = 0;
+    config.retry.attempts = 2;
+
+    let addr = common::spawn_proxy(config)[0];
+
+    // Failing over to the good backend, without ever marking the dead one down.
+    assert!(names(addr, 6)
+        .iter()
+        .all(|name| name.as_deref() == Some(b"good")));
+
+    // Nothing would ever mark it up again, so it's still used once it's back.
+    let listener = net::TcpListener::bind(revived).unwrap();
+    serve(listener, b"revived", Arc::new(AtomicU16::new(200)));
+
+    assert!(names(addr, 4).contains(&Some(b"revived".to_vec())));
+}
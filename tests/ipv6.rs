@@ -0,0 +1,79 @@
+mod common;
+
+use std::net;
+
+use wisp::config::Config;
+
+#[test]
+fn proxy_over_ipv6() {
+    let backend = common::echo_backend("[::1]:0");
+
+    let config = Config {
+        listen: vec!["[::1]:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    let addrs = common::spawn_proxy(config);
+    assert!(addrs[0].is_ipv6());
+
+    let mut stream = net::TcpStream::connect(addrs[0]).unwrap();
+    common::round_trip(&mut stream, b"hello over ipv6");
+}
+
+#[test]
+fn ipv4_frontend_to_ipv6_backend() {
+    let backend = common::echo_backend("[::1]:0");
+
+    let config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    let addrs = common::spawn_proxy(config);
+
+    let mut stream = net::TcpStream::connect(addrs[0]).unwrap();
+    common::round_trip(&mut stream, b"hello from ipv4");
+}
+
+#[test]
+fn dual_stack_listener() {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let config = Config {
+        listen: vec!["[::]:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ..Config::default()
+    };
+
+    let addrs = common::spawn_proxy(config);
+    let port = addrs[0].port();
+
+    // The same listener accepts both address families.
+    let mut stream = net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    common::round_trip(&mut stream, b"ipv4 on a dual-stack listener");
+
+    let mut stream = net::TcpStream::connect(("::1", port)).unwrap();
+    common::round_trip(&mut stream, b"ipv6 on a dual-stack listener");
+}
+
+#[test]
+fn v6_only_listener() {
+    let backend = common::echo_backend("[::1]:0");
+
+    let config = Config {
+        listen: vec!["[::]:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        v6_only: true,
+        ..Config::default()
+    };
+
+    let addrs = common::spawn_proxy(config);
+    let port = addrs[0].port();
+
+    assert!(net::TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    let mut stream = net::TcpStream::connect(("::1", port)).unwrap();
+    common::round_trip(&mut stream, b"ipv6 only");
+}
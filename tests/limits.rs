@@ -0,0 +1,84 @@
+mod common;
+
+use std::{fs, net, thread, time};
+
+use common::Wisp;
+
+// How much CPU the process has used.
+fn cpu(pid: u32) -> time::Duration {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+
+    // The name comes first and may contain spaces, so count the fields after it.
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+
+    let rate = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    time::Duration::from_millis(ticks * 1000 / rate)
+}
+
+fn files(pid: u32) -> u64 {
+    fs::read_dir(format!("/proc/{}/fd", pid)).unwrap().count() as u64
+}
+
+// Start wisp allowed only as many descriptors as it needs for a single connection.
+fn start(backend: &str) -> Wisp {
+    let args = [
+        "--listen",
+        "127.0.0.1:0",
+        "--backend",
+        backend,
+        "--health-interval-ms",
+        "0",
+        "--workers",
+        "1",
+    ];
+
+    // Count what it uses with nothing connected.
+    let idle = Wisp::start(&args);
+    idle.expect("worker 0 using");
+    let idle = files(idle.pid());
+
+    // The limit is inherited, so lower ours while starting it. This is the only test in the binary.
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+        0
+    );
+
+    let lowered = libc::rlimit {
+        rlim_cur: idle + 2,
+        ..limit
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0);
+
+    let wisp = Wisp::start(&args);
+
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+
+    wisp
+}
+
+#[test]
+fn out_of_files() {
+    let backend = common::echo_backend("127.0.0.1:0").to_string();
+    let wisp = start(&backend);
+
+    let mut first = net::TcpStream::connect(wisp.addr).unwrap();
+    common::round_trip(&mut first, b"first");
+
+    // The next connection can't be accepted until the first closes.
+    let mut second = net::TcpStream::connect(wisp.addr).unwrap();
+    wisp.expect("failed to accept");
+
+    // Accepting is retried after a pause, rather than spinning on the error.
+    let before = cpu(wisp.pid());
+    thread::sleep(time::Duration::from_secs(1));
+    let used = cpu(wisp.pid()) - before;
+    assert!(used < time::Duration::from_millis(300), "{:?}", used);
+
+    drop(first);
+    common::round_trip(&mut second, b"second");
+}
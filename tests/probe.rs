@@ -0,0 +1,25 @@
+use io_uring::opcode;
+
+use wisp::kio::Kio;
+
+#[test]
+fn capabilities() {
+    let mut uring = io_uring::IoUring::new(4).unwrap();
+    let kio = Kio::new(&mut uring).unwrap();
+
+    let capabilities = kio.capabilities();
+
+    // Every kernel that can be probed has reads, and the named features match their opcodes.
+    assert!(capabilities.supports(opcode::Read::CODE));
+    assert_eq!(capabilities.splice, kio.supports(opcode::Splice::CODE));
+
+    // Multishot receives arrived after everything else we check for.
+    if capabilities.multishot_recv {
+        assert!(capabilities.direct_accept);
+        assert!(capabilities.multishot_accept && capabilities.buffer_ring);
+        assert_eq!(
+            capabilities.to_string(),
+            "fast-poll direct-accept multishot-accept multishot-recv buffer-ring sqpoll wait-timeout timeout-update send-zc splice"
+        );
+    }
+}
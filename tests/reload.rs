@@ -0,0 +1,98 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::{env, fs, net, process, thread};
+
+use nix::sys::signal::Signal;
+
+use common::Wisp;
+
+// Start a backend that sends its name to every connection and closes it.
+fn named_backend(name: &'static [u8]) -> net::SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.write_all(name);
+        }
+    });
+
+    addr
+}
+
+// Connect through the proxy and return the name of the backend that answered.
+fn name(addr: net::SocketAddr) -> Vec<u8> {
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+
+    let mut name = Vec::new();
+    stream.read_to_end(&mut name).unwrap();
+    name
+}
+
+// The config file, since the backends are reloaded from it.
+fn config_file(backends: &[net::SocketAddr]) -> String {
+    let backends: Vec<String> = backends.iter().map(|b| format!("\"{}\"", b)).collect();
+
+    format!(
+        "listen = [\"127.0.0.1:0\"]\nbackend = [{}]\n\n[health]\ninterval_ms = 0\n",
+        backends.join(", ")
+    )
+}
+
+#[test]
+fn reload_backends() {
+    let a = named_backend(b"a");
+    let b = named_backend(b"b");
+
+    let path = env::temp_dir().join(format!("wisp-reload-{}.toml", process::id()));
+    fs::write(&path, config_file(&[a])).unwrap();
+
+    // Signals are handled once connections are.
+    let mut wisp = Wisp::start(&["--config", path.to_str().unwrap()]);
+    assert_eq!(name(wisp.addr), b"a");
+
+    // The backend that left gets no more connections, and the one that joined gets them at once.
+    fs::write(&path, config_file(&[b])).unwrap();
+    wisp.signal(Signal::SIGHUP);
+    wisp.expect(&format!("backend {} added", b));
+
+    for _ in 0..4 {
+        assert_eq!(name(wisp.addr), b"b");
+    }
+
+    // The backends are kept if the file is invalid.
+    fs::write(&path, "backend = []").unwrap();
+    wisp.signal(Signal::SIGHUP);
+    wisp.expect("failed to reload backends");
+
+    assert_eq!(name(wisp.addr), b"b");
+
+    wisp.signal(Signal::SIGTERM);
+    wisp.wait();
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn backends_from_args_not_reloaded() {
+    let a = named_backend(b"a");
+    let b = named_backend(b"b");
+
+    let path = env::temp_dir().join(format!("wisp-reload-args-{}.toml", process::id()));
+    fs::write(&path, config_file(&[b])).unwrap();
+
+    let backend = a.to_string();
+    let wisp = Wisp::start(&["--config", path.to_str().unwrap(), "--backend", &backend]);
+
+    // Signals are handled once connections are.
+    assert_eq!(name(wisp.addr), b"a");
+
+    // The command line still takes precedence over the file.
+    wisp.signal(Signal::SIGHUP);
+    wisp.expect("backends weren't loaded from a config file");
+
+    assert_eq!(name(wisp.addr), b"a");
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::{net, thread, time};
+
+use wisp::config::Mode;
+
+use common::{config, dead};
+
+// Read everything the proxy sends until it closes the connection, and how long that took.
+fn request(addr: net::SocketAddr) -> (Vec<u8>, time::Duration) {
+    let start = time::Instant::now();
+
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+
+    (response, start.elapsed())
+}
+
+#[test]
+fn failover() {
+    let good = common::echo_backend("127.0.0.1:0");
+    let addr = common::spawn_proxy(config(&[dead(), good, dead()]))[0];
+
+    // Every client reaches the good backend, whichever backend it's sent to first.
+    for _ in 0..6 {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        common::round_trip(&mut stream, b"hello");
+    }
+}
+
+#[test]
+fn retry_until_up() {
+    let backend = dead();
+
+    // Don't let a health check take the first connection.
+    let mut config = config(&[backend]);
+    config.health.interval_ms = 0;
+    config.retry.attempts = 8;
+    config.retry.backoff_ms = 50;
+
+    let addr = common::spawn_proxy(config)[0];
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+
+    // The same backend is tried again after backing off, so the client waits until it's up.
+    thread::sleep(time::Duration::from_millis(200));
+    let listener = net::TcpListener::bind(backend).unwrap();
+    let (mut backend, _) = listener.accept().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 5];
+        backend.read_exact(&mut buf).unwrap();
+        tx.send(buf).unwrap();
+    });
+
+    stream.write_all(b"hello").unwrap();
+    let received = rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    assert_eq!(&received, b"hello");
+}
+
+#[test]
+fn backoff() {
+    let mut config = config(&[dead()]);
+    config.health.interval_ms = 0;
+    config.retry.attempts = 4;
+    config.retry.backoff_ms = 100;
+
+    let addr = common::spawn_proxy(config)[0];
+
+    // Waiting 100, 200 and then 400ms between the attempts.
+    let (response, elapsed) = request(addr);
+    assert!(response.is_empty());
+    assert!(elapsed >= time::Duration::from_millis(700), "{:?}", elapsed);
+    assert!(elapsed < time::Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[test]
+fn deadline() {
+    let mut config = config(&[dead()]);
+    config.retry.attempts = 10;
+    config.retry.backoff_ms = 100;
+    config.retry.deadline_ms = 250;
+
+    let addr = common::spawn_proxy(config)[0];
+
+    // Gives up instead of backing off past the deadline.
+    let (_, elapsed) = request(addr);
+    assert!(elapsed < time::Duration::from_millis(250), "{:?}", elapsed);
+}
+
+#[test]
+fn bad_gateway() {
+    let mut http = config(&[dead(), dead()]);
+    http.mode = Mode::Http;
+
+    let addr = common::spawn_proxy(http)[0];
+
+    let (response, _) = request(addr);
+    assert_eq!(
+        response,
+        b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+
+    // TCP clients are only disconnected, since they may not speak HTTP.
+    let addr = common::spawn_proxy(config(&[dead(), dead()]))[0];
+
+    let (response, _) = request(addr);
+    assert!(response.is_empty());
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::{net, time};
+
+use wisp::config::{self, Config};
+use wisp::kio::completion::CompletionType;
+use wisp::kio::setup::Setup;
+use wisp::kio::Kio;
+
+fn proxy(ring: config::Ring) -> net::SocketAddr {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let mut config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        ring,
+        ..Config::default()
+    };
+
+    config.health.interval_ms = 0;
+    config.workers.count = 2;
+
+    common::spawn_proxy(config)[0]
+}
+
+// Open several connections so both workers are likely to get some.
+fn round_trips(addr: net::SocketAddr) {
+    let mut streams: Vec<net::TcpStream> = (0..16)
+        .map(|_| net::TcpStream::connect(addr).unwrap())
+        .collect();
+
+    for (i, stream) in streams.iter_mut().enumerate() {
+        common::round_trip(stream, &vec![i as u8; 65536]);
+    }
+}
+
+#[test]
+fn sqpoll_shared() {
+    let addr = proxy(config::Ring {
+        sqpoll: true,
+        sqpoll_idle_ms: 10, // sleeps between round trips, so it has to be woken
+        share: true,
+        ..config::Ring::default()
+    });
+
+    round_trips(addr);
+}
+
+#[test]
+fn deferred_task_work() {
+    let addr = proxy(config::Ring {
+        completions: 4096,
+        coop_taskrun: true,
+        single_issuer: true,
+        defer_taskrun: true,
+        ..config::Ring::default()
+    });
+
+    round_trips(addr);
+}
+
+#[test]
+fn setup_flags() {
+    // The kernel only rejects this if the flag made it through.
+    let setup = Setup {
+        defer_taskrun: true,
+        ..Setup::new(4)
+    };
+
+    let err = setup.build().err().unwrap();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    let setup = Setup {
+        single_issuer: true,
+        ..setup
+    };
+
+    let mut uring = setup.build().unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    kio.sleep(time::Duration::from_millis(1));
+
+    match kio.wait().unwrap() {
+        (_, CompletionType::Sleep(_)) => {}
+        _ => panic!("unexpected completion"),
+    }
+}
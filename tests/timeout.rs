@@ -0,0 +1,141 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::{net, thread, time};
+
+use wisp::config::{BufferMode, Config};
+
+const MODES: &[BufferMode] = &[
+    BufferMode::Slice,
+    BufferMode::Provided,
+    BufferMode::Multishot,
+    BufferMode::Fixed,
+    BufferMode::Splice,
+];
+
+fn config(backend: net::SocketAddr, mode: BufferMode) -> Config {
+    let mut config = common::config(&[backend]);
+    config.health.interval_ms = 0;
+    config.buffers.mode = mode;
+    config
+}
+
+// Start a backend that handles a single connection with the given function.
+fn backend<F>(handler: F) -> net::SocketAddr
+where
+    F: FnOnce(net::TcpStream) + Send + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        handler(stream);
+    });
+
+    addr
+}
+
+#[test]
+fn connect_timeout() {
+    // A listener whose queue is full drops new connection attempts, so they never finish.
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = listener.local_addr().unwrap();
+
+    assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+    let _queued = net::TcpStream::connect(backend).unwrap();
+
+    let mut config = config(backend, BufferMode::Provided);
+    config.timeout.connect_ms = 200;
+    config.retry.attempts = 1;
+
+    let addr = common::spawn_proxy(config)[0];
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+
+    assert!(common::closed(&mut stream).unwrap() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn idle_timeout() {
+    for mode in MODES {
+        let (tx, rx) = mpsc::channel();
+
+        let backend = backend(move |mut stream| {
+            tx.send(common::closed(&mut stream).unwrap()).unwrap();
+        });
+
+        let mut config = config(backend, *mode);
+        config.timeout.idle_ms = 200;
+
+        let addr = common::spawn_proxy(config)[0];
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+
+        // Both sides are closed once nothing moves for long enough.
+        let elapsed = common::closed(&mut stream).unwrap();
+        assert!(elapsed >= time::Duration::from_millis(150), "{:?}", mode);
+        assert!(elapsed < time::Duration::from_secs(2), "{:?}", mode);
+
+        let elapsed = rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+        assert!(elapsed < time::Duration::from_secs(2), "{:?}", mode);
+    }
+}
+
+#[test]
+fn write_timeout() {
+    for mode in MODES {
+        let (tx, rx) = mpsc::channel();
+
+        // Send until the proxy stops taking data.
+        let backend = backend(move |mut stream| {
+            let data = [0; 65536];
+            while stream.write_all(&data).is_ok() {}
+
+            tx.send(()).unwrap();
+        });
+
+        let mut config = config(backend, *mode);
+        config.timeout.idle_ms = 0;
+        config.timeout.write_ms = 200;
+
+        let addr = common::spawn_proxy(config)[0];
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+
+        // Never read, so the proxy's writes to us stall and it gives up, closing both sides.
+        rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+        assert!(
+            common::closed(&mut stream).unwrap() < time::Duration::from_secs(2),
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn one_way_transfer() {
+    for mode in MODES {
+        // Keep sending for several idle timeouts without the client sending anything back.
+        let backend = backend(|mut stream| {
+            for i in 0..12u8 {
+                stream.write_all(&[i; 1024]).unwrap();
+                thread::sleep(time::Duration::from_millis(50));
+            }
+        });
+
+        let mut config = config(backend, *mode);
+        config.timeout.idle_ms = 200;
+
+        let addr = common::spawn_proxy(config)[0];
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+
+        assert_eq!(received.len(), 12 * 1024, "{:?}", mode);
+        assert!(received
+            .chunks(1024)
+            .enumerate()
+            .all(|(i, chunk)| chunk[0] == i as u8));
+    }
+}
@@ -0,0 +1,169 @@
+use std::os::unix::io::AsRawFd;
+use std::{net, time};
+
+use wisp::kio::completion::CompletionType;
+use wisp::kio::{task, tcp, Kio};
+
+#[test]
+fn sleep_until() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let deadline = time::Instant::now() + time::Duration::from_millis(20);
+    kio.sleep_until(deadline);
+
+    match kio.wait().unwrap() {
+        (_, CompletionType::Sleep(sleep)) => {
+            sleep.result.unwrap();
+            assert!(sleep.elapsed);
+        }
+        _ => panic!("unexpected completion"),
+    }
+
+    assert!(time::Instant::now() >= deadline);
+}
+
+#[test]
+fn sleep_count() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    // Finishes once the two polls do, long before its own duration.
+    // NOTE: Other sleeps completing don't count.
+    let sleep = task::Sleep::new(time::Duration::from_secs(10)).count(2);
+    let id = kio.run(sleep.into()).detach();
+
+    for _ in 0..2 {
+        let poll = task::Poll {
+            target: tcp::Target::Fd(client.as_raw_fd()),
+            events: libc::POLLOUT,
+        };
+        kio.run(poll.into()).detach();
+    }
+
+    loop {
+        match kio.wait().unwrap() {
+            (task_id, CompletionType::Sleep(sleep)) if task_id == id => {
+                sleep.result.unwrap();
+                assert!(!sleep.elapsed);
+                break;
+            }
+            (_, CompletionType::Poll(_)) => {}
+            _ => panic!("unexpected completion"),
+        }
+    }
+}
+
+#[test]
+fn remove_sleep() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let id = kio.sleep(time::Duration::from_secs(10));
+    kio.remove_sleep(id);
+
+    let mut done = 0;
+    while done < 2 {
+        match kio.wait().unwrap() {
+            (task_id, CompletionType::Sleep(sleep)) if task_id == id => {
+                let err = sleep.result.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+            }
+            (_, CompletionType::TimeoutRemove(remove)) => remove.result.unwrap(),
+            _ => panic!("unexpected completion"),
+        }
+
+        done += 1;
+    }
+}
+
+#[test]
+fn update_sleep() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    if !kio.capabilities().timeout_update {
+        return;
+    }
+
+    let start = time::Instant::now();
+    let id = kio.sleep(time::Duration::from_secs(10));
+    kio.update_sleep(id, time::Duration::from_millis(10));
+
+    let mut done = 0;
+    while done < 2 {
+        match kio.wait().unwrap() {
+            (task_id, CompletionType::Sleep(sleep)) if task_id == id => {
+                sleep.result.unwrap();
+                assert!(sleep.elapsed);
+            }
+            (_, CompletionType::TimeoutUpdate(update)) => update.result.unwrap(),
+            _ => panic!("unexpected completion"),
+        }
+
+        done += 1;
+    }
+
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+}
+
+#[test]
+fn timers() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let start = time::Instant::now();
+
+    // Spread over enough time that most start out in a higher level of the wheel.
+    let mut pending: Vec<_> = (0..1000u64)
+        .map(|i| {
+            let deadline = start + time::Duration::from_millis(i % 300);
+            (kio.timer(deadline), deadline)
+        })
+        .collect();
+
+    // Cancelled timers never expire, and reset ones only at their new deadline.
+    for (timer, _) in pending.drain(..100) {
+        assert!(kio.cancel_timer(timer));
+    }
+
+    for (timer, deadline) in pending.iter_mut().take(100) {
+        *deadline = start + time::Duration::from_millis(350);
+        assert!(kio.reset_timer(*timer, *deadline));
+    }
+
+    while !pending.is_empty() {
+        let timer = match kio.wait().unwrap() {
+            (_, CompletionType::Expired(expired)) => expired.timer,
+            _ => panic!("unexpected completion"),
+        };
+
+        let index = pending.iter().position(|(id, _)| *id == timer).unwrap();
+        let (_, deadline) = pending.swap_remove(index);
+
+        assert!(time::Instant::now() >= deadline);
+    }
+
+    assert_eq!(kio.timers(), 0);
+}
+
+#[test]
+fn earlier_timer() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    // The kernel's timeout is moved up for the second timer, rather than waiting for the first.
+    let late = kio.timer(time::Instant::now() + time::Duration::from_secs(10));
+    let early = kio.timer(time::Instant::now() + time::Duration::from_millis(10));
+
+    match kio.wait().unwrap() {
+        (_, CompletionType::Expired(expired)) => assert_eq!(expired.timer, early),
+        _ => panic!("unexpected completion"),
+    }
+
+    assert!(kio.cancel_timer(late));
+    assert!(!kio.cancel_timer(early));
+}
@@ -0,0 +1,61 @@
+use std::time;
+
+use wisp::kio::completion::CompletionType;
+use wisp::kio::Kio;
+
+#[test]
+fn batch_of_sleeps() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    let mut tasks: Vec<_> = (0..4)
+        .map(|_| kio.sleep(time::Duration::from_millis(10)))
+        .collect();
+
+    // Every sleep should be reaped after a single wait, even if they complete a bit apart.
+    let mut done = Vec::new();
+    while done.len() < tasks.len() {
+        kio.wait_batch(tasks.len() - done.len(), Some(time::Duration::from_secs(5)))
+            .unwrap();
+
+        for (task_id, completion) in kio.ready() {
+            assert!(matches!(completion, CompletionType::Sleep(_)));
+            done.push(task_id);
+        }
+    }
+
+    tasks.sort();
+    done.sort();
+    assert_eq!(tasks, done);
+}
+
+#[test]
+fn batch_timeout() {
+    let mut uring = io_uring::IoUring::new(16).unwrap();
+    let mut kio = Kio::new(&mut uring).unwrap();
+
+    kio.sleep(time::Duration::from_secs(10));
+
+    // Nothing completes in time, so the wait gives up with nothing ready.
+    let start = time::Instant::now();
+    kio.wait_batch(1, Some(time::Duration::from_millis(20)))
+        .unwrap();
+
+    assert!(start.elapsed() >= time::Duration::from_millis(20));
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+    assert!(kio.try_wait().unwrap().is_none());
+
+    // Already ready completions don't wait at all.
+    kio.sleep(time::Duration::ZERO);
+    kio.wait_batch(1, None).unwrap();
+
+    let start = time::Instant::now();
+    kio.wait_batch(1, Some(time::Duration::from_secs(5)))
+        .unwrap();
+
+    assert!(start.elapsed() < time::Duration::from_secs(1));
+    assert!(matches!(
+        kio.try_wait().unwrap(),
+        Some((_, CompletionType::Sleep(_)))
+    ));
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::net;
+
+use wisp::config::{self, Config};
+
+fn proxy(workers: config::Workers) -> net::SocketAddr {
+    let backend = common::echo_backend("127.0.0.1:0");
+
+    let config = Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        backend: vec![backend.into()],
+        workers,
+        ..Config::default()
+    };
+
+    common::spawn_proxy(config)[0]
+}
+
+// Open a bunch of concurrent connections so they're spread over the workers.
+fn round_trips(addr: net::SocketAddr) {
+    let mut streams: Vec<net::TcpStream> = (0..32)
+        .map(|_| net::TcpStream::connect(addr).unwrap())
+        .collect();
+
+    for (i, stream) in streams.iter_mut().enumerate() {
+        common::round_trip(stream, format!("hello {}", i).as_bytes());
+    }
+}
+
+#[test]
+fn multiple_workers() {
+    let addr = proxy(config::Workers {
+        count: 4,
+        ..config::Workers::default()
+    });
+
+    round_trips(addr);
+}
+
+#[test]
+fn pinned_workers_with_steering() {
+    let addr = proxy(config::Workers {
+        count: 0,
+        pin: true,
+        steer: true,
+    });
+
+    round_trips(addr);
+}